@@ -0,0 +1,734 @@
+use crate::errors::BotError;
+use crate::formatting;
+use ab_glyph::{point, Font, FontVec, PxScale, ScaleFont};
+use std::collections::VecDeque;
+use std::fs;
+use std::path::Path;
+use vte::{Params, Perform};
+
+/// Rows the cursor can move back into; older lines are final. Matches the pty size.
+const SCREEN_ROWS: usize = 24;
+const TAB_WIDTH: usize = 8;
+/// Lines wrap here and the cursor can't move further, so escape sequences can't
+/// make a line arbitrarily long.
+const MAX_COLUMNS: usize = 4096;
+
+const IMAGE_FONT_SIZE: f32 = 16.0;
+const IMAGE_PADDING: usize = 12;
+/// Longer lines are wrapped, shorter images are widened so Telegram accepts the aspect ratio.
+const IMAGE_MAX_COLUMNS: usize = 120;
+const IMAGE_MIN_COLUMNS: usize = 40;
+/// Only the last lines are drawn, they usually tell what happened.
+const IMAGE_MAX_LINES: usize = 200;
+const IMAGE_BACKGROUND: (u8, u8, u8) = (30, 30, 30);
+const IMAGE_FOREGROUND: (u8, u8, u8) = (212, 212, 212);
+
+/// The 16 standard colors, as the usual dark terminal themes show them.
+const PALETTE: [(u8, u8, u8); 16] = [
+    (0, 0, 0),
+    (205, 49, 49),
+    (13, 188, 121),
+    (229, 229, 16),
+    (36, 114, 200),
+    (188, 63, 188),
+    (17, 168, 205),
+    (229, 229, 229),
+    (102, 102, 102),
+    (241, 76, 76),
+    (35, 209, 139),
+    (245, 245, 67),
+    (59, 142, 234),
+    (214, 112, 214),
+    (41, 184, 219),
+    (255, 255, 255),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Color {
+    /// One of the 256 xterm colors
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+impl Color {
+    fn rgb(&self) -> (u8, u8, u8) {
+        match *self {
+            Color::Indexed(index @ 0..=15) => PALETTE[index as usize],
+            // The 6x6x6 color cube
+            Color::Indexed(index @ 16..=231) => {
+                let level = |value: u8| if value == 0 { 0 } else { 55 + value * 40 };
+                let index = index - 16;
+                (level(index / 36), level(index / 6 % 6), level(index % 6))
+            }
+            Color::Indexed(index) => {
+                let gray = 8 + (index - 232) * 10;
+                (gray, gray, gray)
+            }
+            Color::Rgb(red, green, blue) => (red, green, blue),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Style {
+    pub foreground: Option<Color>,
+    pub background: Option<Color>,
+    pub bold: bool,
+    pub dim: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub strikethrough: bool,
+    pub inverse: bool,
+}
+
+/// A run of text sharing one style. Lines are separated by `\n` inside the text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub style: Style,
+    pub text: String,
+}
+
+#[derive(Clone, Copy)]
+struct Cell {
+    c: char,
+    style: Style,
+}
+
+impl Cell {
+    const BLANK: Cell = Cell {
+        c: ' ',
+        style: Style {
+            foreground: None,
+            background: None,
+            bold: false,
+            dim: false,
+            italic: false,
+            underline: false,
+            strikethrough: false,
+            inverse: false,
+        },
+    };
+
+    fn is_blank(&self) -> bool {
+        self.c == ' ' && self.style.background.is_none() && !self.style.inverse
+    }
+}
+
+/// Replays output the way a terminal would show it: carriage returns and cursor
+/// movement overwrite earlier text, so progress bars end up as their last state,
+/// and SGR sequences become styles. Everything else is dropped.
+pub struct Terminal {
+    parser: vte::Parser,
+    screen: Screen,
+}
+
+struct Screen {
+    /// Lines that scrolled out of reach of the cursor
+    finished: Vec<Span>,
+    lines: VecDeque<Vec<Cell>>,
+    row: usize,
+    column: usize,
+    style: Style,
+}
+
+impl Terminal {
+    pub fn new() -> Self {
+        Terminal {
+            parser: vte::Parser::new(),
+            screen: Screen {
+                finished: Vec::new(),
+                lines: VecDeque::from([Vec::new()]),
+                row: 0,
+                column: 0,
+                style: Style::default(),
+            },
+        }
+    }
+
+    /// Escape sequences and characters may be split across calls.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.parser.advance(&mut self.screen, bytes);
+    }
+
+    /// Returns everything shown so far, without trailing whitespace, and starts over
+    /// on an empty line. Pending escape sequences and the current style are kept.
+    pub fn take(&mut self) -> Vec<Span> {
+        let screen = &mut self.screen;
+        let mut spans = std::mem::take(&mut screen.finished);
+
+        let line_count = screen.lines.len();
+        for (index, line) in screen.lines.drain(..).enumerate() {
+            push_line(&mut spans, &line, index + 1 < line_count);
+        }
+        screen.lines.push_back(Vec::new());
+        screen.row = 0;
+        screen.column = 0;
+
+        trim_end(&mut spans);
+        spans
+    }
+}
+
+impl Screen {
+    fn line(&mut self) -> &mut Vec<Cell> {
+        &mut self.lines[self.row]
+    }
+
+    fn set_column(&mut self, column: usize) {
+        self.column = column.min(MAX_COLUMNS - 1);
+    }
+
+    /// Moves the cursor down, adding lines as needed and retiring those out of reach.
+    fn move_down(&mut self, count: usize) {
+        self.row += count;
+        while self.lines.len() <= self.row {
+            self.lines.push_back(Vec::new());
+        }
+
+        while self.lines.len() > SCREEN_ROWS && self.row > 0 {
+            if let Some(line) = self.lines.pop_front() {
+                push_line(&mut self.finished, &line, true);
+            }
+            self.row -= 1;
+        }
+    }
+
+    fn erase_line(&mut self, mode: u16) {
+        let column = self.column;
+        let line = self.line();
+        match mode {
+            0 => line.truncate(column),
+            1 => {
+                let end = (column + 1).min(line.len());
+                line[..end].fill(Cell::BLANK);
+            }
+            _ => line.clear(),
+        }
+    }
+
+    fn erase_display(&mut self, mode: u16) {
+        match mode {
+            0 => {
+                self.erase_line(0);
+                self.lines.truncate(self.row + 1);
+            }
+            1 => {
+                for line in self.lines.range_mut(..self.row) {
+                    line.clear();
+                }
+                self.erase_line(1);
+            }
+            mode => {
+                for line in self.lines.iter_mut() {
+                    line.clear();
+                }
+                // 3 also clears the scrollback
+                if mode == 3 {
+                    self.finished.clear();
+                }
+            }
+        }
+    }
+
+    fn select_graphic_rendition(&mut self, params: &Params) {
+        let mut params = params.iter();
+
+        while let Some(param) = params.next() {
+            let style = &mut self.style;
+            match param[0] {
+                0 => *style = Style::default(),
+                1 => style.bold = true,
+                2 => style.dim = true,
+                3 => style.italic = true,
+                4 => style.underline = true,
+                7 => style.inverse = true,
+                9 => style.strikethrough = true,
+                22 => {
+                    style.bold = false;
+                    style.dim = false;
+                }
+                23 => style.italic = false,
+                24 => style.underline = false,
+                27 => style.inverse = false,
+                29 => style.strikethrough = false,
+                code @ 30..=37 => style.foreground = Some(Color::Indexed(code as u8 - 30)),
+                38 => style.foreground = extended_color(param, &mut params),
+                39 => style.foreground = None,
+                code @ 40..=47 => style.background = Some(Color::Indexed(code as u8 - 40)),
+                48 => style.background = extended_color(param, &mut params),
+                49 => style.background = None,
+                code @ 90..=97 => style.foreground = Some(Color::Indexed(code as u8 - 90 + 8)),
+                code @ 100..=107 => style.background = Some(Color::Indexed(code as u8 - 100 + 8)),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Reads a 256 color or true color, given either as `38;5;n` or as `38:5:n`.
+fn extended_color<'a>(param: &[u16], params: &mut impl Iterator<Item = &'a [u16]>) -> Option<Color> {
+    let mut values: Vec<u16> = if param.len() > 1 {
+        param[1..].to_vec()
+    } else {
+        Vec::new()
+    };
+    let mut next = |values: &mut Vec<u16>, index: usize| -> Option<u8> {
+        while values.len() <= index {
+            values.push(params.next()?[0]);
+        }
+        Some(values[index] as u8)
+    };
+
+    match next(&mut values, 0)? {
+        5 => next(&mut values, 1).map(Color::Indexed),
+        2 => {
+            // The colon form may carry a color space id before the components
+            let offset = if param.len() > 5 { 2 } else { 1 };
+            Some(Color::Rgb(
+                next(&mut values, offset)?,
+                next(&mut values, offset + 1)?,
+                next(&mut values, offset + 2)?,
+            ))
+        }
+        _ => None,
+    }
+}
+
+impl Perform for Screen {
+    fn print(&mut self, c: char) {
+        if self.column >= MAX_COLUMNS {
+            self.move_down(1);
+            self.column = 0;
+        }
+
+        let column = self.column;
+        let cell = Cell { c, style: self.style };
+        let line = self.line();
+
+        if line.len() < column {
+            line.resize(column, Cell::BLANK);
+        }
+        if column < line.len() {
+            line[column] = cell;
+        } else {
+            line.push(cell);
+        }
+        self.column += 1;
+    }
+
+    fn execute(&mut self, byte: u8) {
+        match byte {
+            // Pipes don't translate newlines, so a line feed also returns the carriage
+            b'\n' | 0x0b | 0x0c => {
+                self.move_down(1);
+                self.column = 0;
+            }
+            b'\r' => self.column = 0,
+            0x08 => self.column = self.column.saturating_sub(1),
+            b'\t' => self.set_column((self.column / TAB_WIDTH + 1) * TAB_WIDTH),
+            _ => {}
+        }
+    }
+
+    fn csi_dispatch(&mut self, params: &Params, intermediates: &[u8], ignore: bool, action: char) {
+        // Private modes like cursor visibility don't change what is shown
+        if ignore || !intermediates.is_empty() {
+            return;
+        }
+
+        let raw = |index: usize| params.iter().nth(index).map(|param| param[0]).unwrap_or(0);
+        let count = |index: usize| raw(index).max(1) as usize;
+
+        match action {
+            'm' => self.select_graphic_rendition(params),
+            'A' => self.row = self.row.saturating_sub(count(0)),
+            'B' | 'e' => self.move_down(count(0).min(SCREEN_ROWS - 1)),
+            'C' | 'a' => self.set_column(self.column + count(0)),
+            'D' => self.column = self.column.saturating_sub(count(0)),
+            'E' => {
+                self.move_down(count(0).min(SCREEN_ROWS - 1));
+                self.column = 0;
+            }
+            'F' => {
+                self.row = self.row.saturating_sub(count(0));
+                self.column = 0;
+            }
+            'G' | '`' => self.set_column(count(0) - 1),
+            'H' | 'f' => {
+                let row = (count(0) - 1).min(SCREEN_ROWS - 1);
+                if row >= self.row {
+                    self.move_down(row - self.row);
+                } else {
+                    self.row = row;
+                }
+                self.set_column(count(1) - 1);
+            }
+            'K' => self.erase_line(raw(0)),
+            'J' => self.erase_display(raw(0)),
+            'X' => {
+                let column = self.column;
+                let line = self.line();
+                let end = (column + count(0)).min(line.len());
+                if column < end {
+                    line[column..end].fill(Cell::BLANK);
+                }
+            }
+            'P' => {
+                let column = self.column;
+                let line = self.line();
+                let end = (column + count(0)).min(line.len());
+                if column < end {
+                    line.drain(column..end);
+                }
+            }
+            '@' => {
+                let column = self.column;
+                let line = self.line();
+                if column < line.len() {
+                    let inserted = count(0).min(MAX_COLUMNS - column);
+                    line.splice(column..column, std::iter::repeat_n(Cell::BLANK, inserted));
+                    line.truncate(MAX_COLUMNS);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn push_text(spans: &mut Vec<Span>, text: &str, style: Style) {
+    match spans.last_mut() {
+        Some(last) if last.style == style => last.text.push_str(text),
+        _ => spans.push(Span {
+            style,
+            text: text.to_string(),
+        }),
+    }
+}
+
+fn push_line(spans: &mut Vec<Span>, line: &[Cell], newline: bool) {
+    let end = line.iter().rposition(|cell| !cell.is_blank()).map_or(0, |index| index + 1);
+
+    let mut buffer = [0u8; 4];
+    for cell in &line[..end] {
+        push_text(spans, cell.c.encode_utf8(&mut buffer), cell.style);
+    }
+
+    if newline {
+        // Keeps styled runs together across lines
+        let style = spans.last().map(|span| span.style).unwrap_or_default();
+        push_text(spans, "\n", style);
+    }
+}
+
+fn trim_end(spans: &mut Vec<Span>) {
+    while let Some(last) = spans.last_mut() {
+        let trimmed = last.text.trim_end().len();
+        if trimmed > 0 {
+            last.text.truncate(trimmed);
+            break;
+        }
+        spans.pop();
+    }
+}
+
+/// Interprets the complete output of a command.
+pub fn parse(text: &str) -> Vec<Span> {
+    let mut terminal = Terminal::new();
+    terminal.feed(text.as_bytes());
+    terminal.take()
+}
+
+pub fn plain_text(spans: &[Span]) -> String {
+    spans.iter().map(|span| span.text.as_str()).collect()
+}
+
+/// Telegram HTML can't color text, and `pre` blocks can't be styled, so this keeps
+/// bold, italic, underline and strikethrough in regular text.
+pub fn html(spans: &[Span]) -> String {
+    let mut html = String::new();
+
+    for span in spans {
+        let mut text = formatting::escape_html(&span.text);
+        if !span.text.trim().is_empty() {
+            let style = &span.style;
+            for (enabled, tag) in [
+                (style.strikethrough, "s"),
+                (style.underline, "u"),
+                (style.italic, "i"),
+                (style.bold, "b"),
+            ] {
+                if enabled {
+                    text = format!("<{}>{}</{}>", tag, text, tag);
+                }
+            }
+        }
+        html.push_str(&text);
+    }
+
+    html
+}
+
+/// [`formatting::text_len`] over all spans.
+pub fn text_len(spans: &[Span]) -> usize {
+    spans.iter().map(|span| formatting::text_len(&span.text)).sum()
+}
+
+/// [`formatting::head`], keeping the styles of the spans.
+pub fn head(spans: &[Span], max_len: usize) -> Vec<Span> {
+    let mut result = Vec::new();
+    let mut room = max_len;
+
+    for span in spans {
+        let text = formatting::head(&span.text, room);
+        room -= formatting::text_len(text);
+        if !text.is_empty() {
+            result.push(Span {
+                style: span.style,
+                text: text.to_string(),
+            });
+        }
+        if text.len() < span.text.len() {
+            break;
+        }
+    }
+
+    result
+}
+
+/// [`formatting::tail`], keeping the styles of the spans.
+pub fn tail(spans: &[Span], max_len: usize) -> Vec<Span> {
+    let mut result = Vec::new();
+    let mut room = max_len;
+
+    for span in spans.iter().rev() {
+        let text = formatting::tail(&span.text, room);
+        room -= formatting::text_len(text);
+        if !text.is_empty() {
+            result.push(Span {
+                style: span.style,
+                text: text.to_string(),
+            });
+        }
+        if text.len() < span.text.len() {
+            break;
+        }
+    }
+
+    result.reverse();
+    result
+}
+
+fn cell_colors(style: &Style) -> ((u8, u8, u8), (u8, u8, u8)) {
+    // Bold text in one of the eight base colors shows in its bright variant
+    let foreground = match style.foreground {
+        Some(Color::Indexed(index @ 0..=7)) if style.bold => Color::Indexed(index + 8).rgb(),
+        Some(color) => color.rgb(),
+        None => IMAGE_FOREGROUND,
+    };
+    let background = style.background.map_or(IMAGE_BACKGROUND, |color| color.rgb());
+
+    let (foreground, background) = if style.inverse {
+        (background, foreground)
+    } else {
+        (foreground, background)
+    };
+
+    if style.dim {
+        (blend(background, foreground, 0.5), background)
+    } else {
+        (foreground, background)
+    }
+}
+
+fn blend(base: (u8, u8, u8), color: (u8, u8, u8), coverage: f32) -> (u8, u8, u8) {
+    let mix = |base: u8, color: u8| (base as f32 + (color as f32 - base as f32) * coverage).round() as u8;
+    (mix(base.0, color.0), mix(base.1, color.1), mix(base.2, color.2))
+}
+
+/// Draws the text like a dark terminal window, with the monospace font at `font_path`.
+pub fn render_png(spans: &[Span], font_path: &Path) -> Result<Vec<u8>, BotError> {
+    let font_data = fs::read(font_path)
+        .map_err(|e| BotError::ExecError(format!("Failed to read font {}: {}", font_path.display(), e)))?;
+    let font = FontVec::try_from_vec(font_data)
+        .map_err(|e| BotError::ExecError(format!("Failed to load font {}: {}", font_path.display(), e)))?;
+
+    let scale = PxScale::from(IMAGE_FONT_SIZE);
+    let scaled_font = font.as_scaled(scale);
+    let cell_width = scaled_font.h_advance(font.glyph_id('M')).ceil() as usize;
+    let cell_height = scaled_font.height().ceil() as usize;
+    let ascent = scaled_font.ascent();
+
+    // Splits into lines of cells, wrapping long ones
+    let mut lines: Vec<Vec<(char, Style)>> = vec![Vec::new()];
+    for span in spans {
+        for c in span.text.chars() {
+            if c == '\n' {
+                lines.push(Vec::new());
+                continue;
+            }
+            if lines.last().is_some_and(|line| line.len() == IMAGE_MAX_COLUMNS) {
+                lines.push(Vec::new());
+            }
+            if let Some(line) = lines.last_mut() {
+                line.push((c, span.style));
+            }
+        }
+    }
+    let lines = &lines[lines.len().saturating_sub(IMAGE_MAX_LINES)..];
+
+    let columns = lines
+        .iter()
+        .map(|line| line.len())
+        .max()
+        .unwrap_or(0)
+        .clamp(IMAGE_MIN_COLUMNS, IMAGE_MAX_COLUMNS);
+    let width = columns * cell_width + 2 * IMAGE_PADDING;
+    let height = lines.len() * cell_height + 2 * IMAGE_PADDING;
+
+    let mut pixels = Vec::with_capacity(width * height * 3);
+    for _ in 0..width * height {
+        pixels.extend_from_slice(&[IMAGE_BACKGROUND.0, IMAGE_BACKGROUND.1, IMAGE_BACKGROUND.2]);
+    }
+
+    let mut paint = |x: usize, y: usize, color: (u8, u8, u8), coverage: f32| {
+        if x >= width || y >= height {
+            return;
+        }
+        let offset = (y * width + x) * 3;
+        let base = (pixels[offset], pixels[offset + 1], pixels[offset + 2]);
+        let (red, green, blue) = blend(base, color, coverage.clamp(0.0, 1.0));
+        pixels[offset..offset + 3].copy_from_slice(&[red, green, blue]);
+    };
+
+    for (row, line) in lines.iter().enumerate() {
+        let top = IMAGE_PADDING + row * cell_height;
+
+        for (column, (c, style)) in line.iter().enumerate() {
+            let left = IMAGE_PADDING + column * cell_width;
+            let (foreground, background) = cell_colors(style);
+
+            if background != IMAGE_BACKGROUND {
+                for y in top..top + cell_height {
+                    for x in left..left + cell_width {
+                        paint(x, y, background, 1.0);
+                    }
+                }
+            }
+
+            // Bold is faked by drawing the glyph a second time, one pixel to the right
+            let offsets: &[f32] = if style.bold { &[0.0, 1.0] } else { &[0.0] };
+            for offset in offsets {
+                let glyph = font
+                    .glyph_id(*c)
+                    .with_scale_and_position(scale, point(left as f32 + offset, top as f32 + ascent));
+                if let Some(outline) = font.outline_glyph(glyph) {
+                    let bounds = outline.px_bounds();
+                    outline.draw(|x, y, coverage| {
+                        let x = bounds.min.x as i64 + x as i64;
+                        let y = bounds.min.y as i64 + y as i64;
+                        if x >= 0 && y >= 0 {
+                            paint(x as usize, y as usize, foreground, coverage);
+                        }
+                    });
+                }
+            }
+
+            let mut rule = |y: usize| {
+                for x in left..left + cell_width {
+                    paint(x, y, foreground, 1.0);
+                }
+            };
+            if style.underline {
+                rule(top + ascent.ceil() as usize + 1);
+            }
+            if style.strikethrough {
+                rule(top + cell_height / 2);
+            }
+        }
+    }
+
+    let mut png_data = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png_data, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder
+        .write_header()
+        .map_err(|e| BotError::ExecError(format!("Failed to encode output image: {}", e)))?;
+    writer
+        .write_image_data(&pixels)
+        .map_err(|e| BotError::ExecError(format!("Failed to encode output image: {}", e)))?;
+    writer
+        .finish()
+        .map_err(|e| BotError::ExecError(format!("Failed to encode output image: {}", e)))?;
+
+    Ok(png_data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursor_movement_is_bounded() {
+        let text = "\x1b[65535C\x1b[65535@x".repeat(2000);
+        let output = plain_text(&parse(&text));
+        let longest = output.lines().map(|line| line.chars().count()).max();
+        assert!(longest <= Some(MAX_COLUMNS), "{:?}", longest);
+
+        let text = "\x1b[65535Bx".repeat(2000);
+        assert!(plain_text(&parse(&text)).lines().count() <= 2000 * SCREEN_ROWS);
+    }
+
+    #[test]
+    fn long_lines_wrap() {
+        let text = "x".repeat(MAX_COLUMNS + 10);
+        let output = plain_text(&parse(&text));
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].len(), MAX_COLUMNS);
+        assert_eq!(lines[1].len(), 10);
+    }
+
+    #[test]
+    fn carriage_returns_overwrite_progress_bars() {
+        let text = "Downloading\n[==   ] 40%\r[==== ] 80%\r[=====] 100%\ndone\n";
+        assert_eq!(plain_text(&parse(text)), "Downloading\n[=====] 100%\ndone");
+    }
+
+    #[test]
+    fn sgr_sequences_become_styles_until_reset() {
+        let spans = parse("\x1b[1;31merror\x1b[m: failed");
+        assert_eq!(plain_text(&spans), "error: failed");
+        assert_eq!(spans[0].text, "error");
+        assert!(spans[0].style.bold);
+        assert_eq!(spans[0].style.foreground, Some(Color::Indexed(1)));
+        assert_eq!(spans[1].style, Style::default());
+
+        assert_eq!(html(&spans), "<b>error</b>: failed");
+    }
+
+    #[test]
+    fn html_escapes_styled_text() {
+        let spans = parse("\x1b[4m</b> & <i>\x1b[0m <");
+        assert_eq!(html(&spans), "<u>&lt;/b&gt; &amp; &lt;i&gt;</u> &lt;");
+    }
+
+    #[test]
+    fn head_and_tail_keep_styles_across_spans() {
+        let spans = parse("plain \x1b[1mbold\x1b[0m end");
+
+        let beginning = head(&spans, 8);
+        assert_eq!(plain_text(&beginning), "plain bo");
+        assert!(beginning[1].style.bold);
+        assert_eq!(text_len(&beginning), 8);
+
+        let end = tail(&spans, 6);
+        assert_eq!(plain_text(&end), "ld end");
+        assert!(end[0].style.bold);
+        assert!(!end[1].style.bold);
+
+        assert!(head(&spans, 0).is_empty());
+        assert_eq!(tail(&spans, 100), spans);
+    }
+}
@@ -0,0 +1,54 @@
+use teloxide::types::Message;
+
+/// A file sent to the bot, reduced to what's needed to download and store it.
+#[derive(Debug, Clone)]
+pub struct Attachment {
+    pub file_id: String,
+    pub size: u32,
+    pub file_name: String,
+}
+
+impl Attachment {
+    /// Extracts a document, photo, audio or video from the message. The caption,
+    /// when present, replaces the original file name.
+    pub fn from_message(msg: &Message) -> Option<Self> {
+        let (file, original_name) = if let Some(document) = msg.document() {
+            let name = document
+                .file_name
+                .clone()
+                .unwrap_or_else(|| format!("document_{}", document.file.unique_id));
+            (&document.file, name)
+        } else if let Some(photo) = msg
+            .photo()
+            .and_then(|sizes| sizes.iter().max_by_key(|size| size.width * size.height))
+        {
+            (&photo.file, format!("photo_{}.jpg", photo.file.unique_id))
+        } else if let Some(audio) = msg.audio() {
+            let name = audio
+                .file_name
+                .clone()
+                .unwrap_or_else(|| format!("audio_{}.mp3", audio.file.unique_id));
+            (&audio.file, name)
+        } else if let Some(video) = msg.video() {
+            let name = video
+                .file_name
+                .clone()
+                .unwrap_or_else(|| format!("video_{}.mp4", video.file.unique_id));
+            (&video.file, name)
+        } else {
+            return None;
+        };
+
+        let file_name = msg
+            .caption()
+            .map(|caption| caption.trim().to_string())
+            .filter(|caption| !caption.is_empty())
+            .unwrap_or(original_name);
+
+        Some(Attachment {
+            file_id: file.id.clone(),
+            size: file.size,
+            file_name,
+        })
+    }
+}
@@ -1,25 +1,151 @@
 use crate::errors::BotError;
-use crate::types::{AuthorizedUsers, UserInfo};
-use rand::Rng;
-use rand::distr::{Alphanumeric, Uniform, StandardUniform};
+use crate::policy::Policy;
+use crate::totp;
+use crate::types::{AuthorizedUsers, Config, Invite, Permission, Role, TotpEnrollment, UserInfo};
+use rand::distr::Alphanumeric;
+use rand::RngExt;
+use regex::Regex;
 use std::collections::HashMap;
+use std::fmt;
 use std::fs;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const INVITE_CODE_LENGTH: usize = 12;
+const INVITE_TTL: Duration = Duration::from_secs(24 * 3600);
+const LAST_SEEN_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+const MAX_LOCKOUT: Duration = Duration::from_secs(24 * 3600);
+const ADMIN_NOTIFICATION_INTERVAL: Duration = Duration::from_secs(60);
+/// Failures are forgotten after this long without another one, which also
+/// resets the growing lockout.
+const FAILURE_MEMORY: Duration = MAX_LOCKOUT;
+
+struct AccessCode {
+    code: String,
+    expires_at: Instant,
+    attempts: u32,
+    /// When the admins were last sent a code for this user.
+    admins_notified_at: Option<Instant>,
+}
+
+/// A freshly generated access code.
+pub struct AccessCodeRequest {
+    pub code: String,
+    /// Admins hear about each user at most once per `ADMIN_NOTIFICATION_INTERVAL`,
+    /// so repeated requests can't flood them.
+    pub notify_admins: bool,
+}
+
+struct FailedAttempts {
+    count: u32,
+    locked_until: Option<Instant>,
+    last_failure: Instant,
+}
+
+/// Where the manager reads the time from, so tests can move it forward.
+#[derive(Default)]
+struct Clock {
+    offset: Duration,
+}
+
+impl Clock {
+    fn instant(&self) -> Instant {
+        Instant::now() + self.offset
+    }
+
+    fn unix_time(&self) -> Result<u64, BotError> {
+        Ok((SystemTime::now() + self.offset)
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| BotError::AuthError(e.to_string()))?
+            .as_secs())
+    }
+
+    #[cfg(test)]
+    fn advance(&mut self, by: Duration) {
+        self.offset += by;
+    }
+}
+
+/// Outcome of redeeming an access code or invite.
+pub enum VerifyStatus {
+    Authorized(Role),
+    /// Wrong code; the user's own pending code survives this many more tries.
+    Invalid { attempts_left: Option<u32> },
+    /// The code was right but has expired, or was burned by too many wrong tries.
+    Expired,
+    LockedOut(Duration),
+}
+
+/// Why an authorization ended on its own.
+pub enum SessionExpiry {
+    Idle,
+    MaxAge,
+}
+
+impl fmt::Display for SessionExpiry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SessionExpiry::Idle => write!(f, "inactive for too long"),
+            SessionExpiry::MaxAge => write!(f, "reached its maximum age"),
+        }
+    }
+}
 
 pub struct AuthManager {
     authorized_users: AuthorizedUsers,
     auth_file_path: String,
-    access_codes: HashMap<String, i64>, // code -> user_id
+    access_codes: HashMap<i64, AccessCode>, // user_id -> code
+    failed_attempts: HashMap<i64, FailedAttempts>,
+    default_role: Role,
+    code_ttl: Duration,
+    code_max_attempts: u32,
+    max_failures: u32,
+    lockout: Duration,
+    max_age: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    last_seen_saved_at: Instant,
+    totp_sensitive_patterns: Vec<Regex>,
+    totp_freshness: Duration,
+    totp_verified_at: HashMap<i64, Instant>,
+    /// Last accepted step per user, so a code can't be replayed within its window.
+    totp_last_step: HashMap<i64, u64>,
+    clock: Clock,
 }
 
 impl AuthManager {
-    pub fn new(auth_file_path: &str) -> Result<Self, BotError> {
-        let authorized_users = Self::load_authorized_users(auth_file_path)?;
+    pub fn new(config: &Config) -> Result<Self, BotError> {
+        let authorized_users = Self::load_authorized_users(&config.auth_file_path)?;
+        let totp_sensitive_patterns = config
+            .totp_sensitive_patterns
+            .iter()
+            .map(|pattern| {
+                Regex::new(pattern).map_err(|e| {
+                    BotError::ConfigError(format!("Invalid TOTP pattern '{}': {}", pattern, e))
+                })
+            })
+            .collect::<Result<_, _>>()?;
 
         Ok(AuthManager {
             authorized_users,
-            auth_file_path: auth_file_path.to_string(),
+            auth_file_path: config.auth_file_path.clone(),
             access_codes: HashMap::new(),
+            failed_attempts: HashMap::new(),
+            default_role: config.default_role,
+            code_ttl: Duration::from_secs(config.access_code_ttl_secs),
+            code_max_attempts: config.access_code_max_attempts.max(1),
+            max_failures: config.auth_max_failures.max(1),
+            lockout: Duration::from_secs(config.auth_lockout_secs),
+            max_age: Some(Duration::from_secs(config.auth_max_age_secs))
+                .filter(|max_age| !max_age.is_zero()),
+            idle_timeout: Some(Duration::from_secs(config.auth_idle_timeout_secs))
+                .filter(|idle_timeout| !idle_timeout.is_zero()),
+            last_seen_saved_at: Instant::now(),
+            totp_sensitive_patterns,
+            totp_freshness: Duration::from_secs(config.totp_freshness_secs),
+            totp_verified_at: HashMap::new(),
+            totp_last_step: HashMap::new(),
+            clock: Clock::default(),
         })
     }
 
@@ -29,56 +155,747 @@ impl AuthManager {
                 serde_json::from_str(&content)
                     .map_err(|e| BotError::AuthError(format!("Failed to parse auth file: {}", e)))
             }
-            Err(_) => Ok(AuthorizedUsers { users: HashMap::new() }),
+            Err(_) => Ok(AuthorizedUsers {
+                users: HashMap::new(),
+                totp: HashMap::new(),
+                signed_out_roles: HashMap::new(),
+                invites: HashMap::new(),
+            }),
         }
     }
 
+    /// Holds TOTP secrets and invites, so it's only readable by the bot's user, and
+    /// replaced in one step so a crash can't leave half of it behind.
     fn save_authorized_users(&self) -> Result<(), BotError> {
         let content = serde_json::to_string_pretty(&self.authorized_users)
             .map_err(|e| BotError::SerializationError(e.to_string()))?;
+        let temporary_path = format!("{}.tmp", self.auth_file_path);
+
+        let result = (|| {
+            // A leftover would keep its permissions, as the mode only applies to new files
+            match fs::remove_file(&temporary_path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
 
-        fs::write(&self.auth_file_path, content)
-            .map_err(|e| BotError::AuthError(format!("Failed to save auth file: {}", e)))?;
+            let mut file = fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&temporary_path)?;
+            file.write_all(content.as_bytes())?;
+            file.sync_all()?;
+            fs::rename(&temporary_path, &self.auth_file_path)
+        })();
 
-        Ok(())
+        result.map_err(|e| {
+            let _ = fs::remove_file(&temporary_path);
+            BotError::AuthError(format!("Failed to save auth file: {}", e))
+        })
     }
 
-    pub fn generate_access_code(&mut self, user_id: i64) -> String {
+    /// Remaining lockout of a user after too many failed verifications.
+    pub fn lockout_remaining(&self, user_id: i64) -> Option<Duration> {
+        self.failed_attempts
+            .get(&user_id)
+            .and_then(|failed| failed.locked_until)
+            .and_then(|until| until.checked_duration_since(self.clock.instant()))
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    /// Creates a new code for the user, replacing any code requested earlier.
+    pub fn generate_access_code(&mut self, user_id: i64) -> Result<AccessCodeRequest, BotError> {
+        if let Some(remaining) = self.lockout_remaining(user_id) {
+            return Err(BotError::AuthError(format!(
+                "Too many failed attempts, try again in {}s",
+                remaining.as_secs() + 1
+            )));
+        }
+
+        self.prune(user_id);
+        let now = self.clock.instant();
+        let admins_notified_at = self
+            .access_codes
+            .get(&user_id)
+            .and_then(|access_code| access_code.admins_notified_at)
+            .filter(|notified_at| now.duration_since(*notified_at) < ADMIN_NOTIFICATION_INTERVAL);
+        let notify_admins = admins_notified_at.is_none();
+
         let mut rng = rand::rng();
         let random_number = rng.random_range(100000..=999999);
         let code = random_number.to_string();
 
-        self.access_codes.insert(code.clone(), user_id);
-        code
-    }
-
-    pub fn verify_access_code(&mut self, code: &str, user_id: i64, username: Option<String>) -> Result<bool, BotError> {
-        if let Some(stored_user_id) = self.access_codes.get(code) {
-            if *stored_user_id == user_id {
-                let user_info = UserInfo {
-                    user_id,
-                    username,
-                    authorized_at: SystemTime::now()
-                        .duration_since(UNIX_EPOCH)
-                        .map_err(|e| BotError::AuthError(e.to_string()))?
-                        .as_secs()
-                        .to_string(),
-                };
-
-                self.authorized_users.users.insert(user_id, user_info);
-                self.access_codes.remove(code);
+        self.access_codes.insert(
+            user_id,
+            AccessCode {
+                code: code.clone(),
+                expires_at: now + self.code_ttl,
+                attempts: 0,
+                admins_notified_at: admins_notified_at.or(Some(now)),
+            },
+        );
+        Ok(AccessCodeRequest { code, notify_admins })
+    }
+
+    /// Creates a one-time code that authorizes whoever redeems it with the given role.
+    pub fn create_invite(&mut self, role: Role) -> Result<String, BotError> {
+        let code: String = rand::rng()
+            .sample_iter(Alphanumeric)
+            .take(INVITE_CODE_LENGTH)
+            .map(char::from)
+            .collect();
+
+        let now = self.clock.unix_time()?;
+        let invites = &mut self.authorized_users.invites;
+        invites.retain(|_, invite| invite.expires_at > now);
+        invites.insert(
+            code.clone(),
+            Invite {
+                role,
+                expires_at: now + INVITE_TTL.as_secs(),
+            },
+        );
+        self.save_authorized_users()?;
+        Ok(code)
+    }
+
+    pub fn verify_access_code(
+        &mut self,
+        code: &str,
+        user_id: i64,
+        username: Option<String>,
+    ) -> Result<VerifyStatus, BotError> {
+        if let Some(remaining) = self.lockout_remaining(user_id) {
+            return Ok(VerifyStatus::LockedOut(remaining));
+        }
+
+        self.prune(user_id);
+        let now = self.clock.instant();
+        let code = code.trim();
+
+        let own_code_matches = self
+            .access_codes
+            .get(&user_id)
+            .map(|access_code| access_code.code == code);
+
+        let role = if own_code_matches == Some(true) {
+            let access_code = self.access_codes.remove(&user_id);
+            if access_code.is_some_and(|access_code| access_code.expires_at <= now) {
+                return Ok(VerifyStatus::Expired);
+            }
+
+            // Returning users keep their role, the first user becomes admin so
+            // somebody can manage the others
+            if let Some(role) = self.authorized_users.signed_out_roles.get(&user_id) {
+                *role
+            } else if self.count_admins() > 0 {
+                self.default_role
+            } else {
+                Role::Admin
+            }
+        } else if let Some(invite) = self.authorized_users.invites.remove(code) {
+            if invite.expires_at <= self.clock.unix_time()? {
                 self.save_authorized_users()?;
-                return Ok(true);
+                return Ok(VerifyStatus::Expired);
+            }
+            invite.role
+        } else if let Some(role) = self.check_totp_code(user_id, code)? {
+            role
+        } else {
+            return Ok(self.record_failure(user_id));
+        };
+
+        self.failed_attempts.remove(&user_id);
+        self.authorized_users.signed_out_roles.remove(&user_id);
+
+        let user_info = UserInfo {
+            user_id,
+            username,
+            authorized_at: self.clock.unix_time()?.to_string(),
+            role,
+            last_seen: None,
+        };
+
+        self.authorized_users.users.insert(user_id, user_info);
+        self.save_authorized_users()?;
+        Ok(VerifyStatus::Authorized(role))
+    }
+
+    /// Returns the enrolled role if the code is a fresh TOTP code of the user.
+    fn check_totp_code(&mut self, user_id: i64, code: &str) -> Result<Option<Role>, BotError> {
+        let Some(enrollment) = self.authorized_users.totp.get(&user_id) else {
+            return Ok(None);
+        };
+
+        let step = match totp::verify(&enrollment.secret, code, self.clock.unix_time()?)? {
+            Some(step) => step,
+            None => return Ok(None),
+        };
+
+        if self.totp_last_step.get(&user_id).is_some_and(|last| *last >= step) {
+            return Ok(None);
+        }
+
+        let role = enrollment.role;
+        self.totp_last_step.insert(user_id, step);
+        self.totp_verified_at.insert(user_id, self.clock.instant());
+        Ok(Some(role))
+    }
+
+    /// Generates a new TOTP secret for the user, replacing an earlier one.
+    pub fn enroll_totp(&mut self, user_id: i64, role: Role) -> Result<String, BotError> {
+        let secret = totp::generate_secret();
+
+        self.authorized_users.totp.insert(
+            user_id,
+            TotpEnrollment {
+                secret: secret.clone(),
+                role,
+            },
+        );
+        self.totp_last_step.remove(&user_id);
+        self.save_authorized_users()?;
+        Ok(secret)
+    }
+
+    pub fn disable_totp(&mut self, user_id: i64) -> Result<bool, BotError> {
+        self.totp_verified_at.remove(&user_id);
+        if self.authorized_users.totp.remove(&user_id).is_none() {
+            return Ok(false);
+        }
+
+        self.save_authorized_users()?;
+        Ok(true)
+    }
+
+    pub fn has_totp(&self, user_id: i64) -> bool {
+        self.authorized_users.totp.contains_key(&user_id)
+    }
+
+    /// Confirms the user is holding their authenticator, for sensitive commands.
+    pub fn verify_totp(&mut self, user_id: i64, code: &str) -> Result<VerifyStatus, BotError> {
+        if let Some(remaining) = self.lockout_remaining(user_id) {
+            return Ok(VerifyStatus::LockedOut(remaining));
+        }
+
+        match self.check_totp_code(user_id, code)? {
+            Some(role) => {
+                self.failed_attempts.remove(&user_id);
+                Ok(VerifyStatus::Authorized(role))
+            }
+            None => Ok(self.record_failure(user_id)),
+        }
+    }
+
+    /// Patterns see each command of the line on its own, like confirm patterns do,
+    /// so `ls; sudo x`, `'sudo' x` and `env sudo x` all match `^sudo\b`.
+    pub fn is_sensitive(&self, command: &str) -> bool {
+        Policy::command_texts(command).iter().any(|text| {
+            self.totp_sensitive_patterns
+                .iter()
+                .any(|pattern| pattern.is_match(text))
+        })
+    }
+
+    fn has_fresh_totp(&self, user_id: i64) -> bool {
+        self.totp_verified_at
+            .get(&user_id)
+            .is_some_and(|verified_at| {
+                self.clock.instant().duration_since(*verified_at) < self.totp_freshness
+            })
+    }
+
+    /// Whether the command may only run after a recent TOTP confirmation.
+    pub fn requires_fresh_totp(&self, user_id: i64, command: &str) -> bool {
+        self.is_sensitive(command) && !self.has_fresh_totp(user_id)
+    }
+
+    /// Whether starting an interactive shell needs a recent TOTP confirmation,
+    /// which is the case as soon as any command is considered sensitive.
+    pub fn shell_requires_fresh_totp(&self, user_id: i64) -> bool {
+        !self.totp_sensitive_patterns.is_empty() && !self.has_fresh_totp(user_id)
+    }
+
+    fn record_failure(&mut self, user_id: i64) -> VerifyStatus {
+        let now = self.clock.instant();
+        let attempts_left = match self.access_codes.get_mut(&user_id) {
+            Some(access_code) => {
+                access_code.attempts += 1;
+                let attempts_left = self.code_max_attempts.saturating_sub(access_code.attempts);
+
+                if attempts_left == 0 || access_code.expires_at <= now {
+                    self.access_codes.remove(&user_id);
+                    None
+                } else {
+                    Some(attempts_left)
+                }
             }
+            None => None,
+        };
+
+        let failed = self.failed_attempts.entry(user_id).or_insert(FailedAttempts {
+            count: 0,
+            locked_until: None,
+            last_failure: now,
+        });
+        failed.count += 1;
+        failed.last_failure = now;
+
+        // Every failure past the limit doubles the lockout
+        if failed.count >= self.max_failures {
+            let exponent = (failed.count - self.max_failures).min(16);
+            let lockout = self.lockout.saturating_mul(1 << exponent).min(MAX_LOCKOUT);
+
+            failed.locked_until = Some(now + lockout);
+            // A locked out user has to request a new code afterwards
+            self.access_codes.remove(&user_id);
+            return VerifyStatus::LockedOut(lockout);
         }
-        Ok(false)
+
+        VerifyStatus::Invalid { attempts_left }
+    }
+
+    /// Drops expired codes and forgotten failures, so requests from strangers
+    /// can't grow them without bound. The user's own code is kept, to tell them it
+    /// expired rather than that it's wrong.
+    fn prune(&mut self, user_id: i64) {
+        let now = self.clock.instant();
+
+        self.access_codes
+            .retain(|id, access_code| *id == user_id || access_code.expires_at > now);
+        self.failed_attempts
+            .retain(|_, failed| now.duration_since(failed.last_failure) < FAILURE_MEMORY);
+    }
+
+    /// Signed out admins count too, they can come back at any time.
+    fn count_admins(&self) -> usize {
+        let users = &self.authorized_users;
+        users
+            .users
+            .values()
+            .map(|user| user.role)
+            .chain(users.signed_out_roles.values().copied())
+            .filter(|role| *role == Role::Admin)
+            .count()
     }
 
     pub fn is_authorized(&self, user_id: i64) -> bool {
         self.authorized_users.users.contains_key(&user_id)
     }
 
+    pub fn get_role(&self, user_id: i64) -> Option<Role> {
+        self.authorized_users.users.get(&user_id).map(|user| user.role)
+    }
+
+    pub fn has_permission(&self, user_id: i64, permission: Permission) -> bool {
+        self.get_role(user_id)
+            .is_some_and(|role| role.has_permission(permission))
+    }
+
+    /// De-authorizes the user if their authorization has expired, otherwise records
+    /// the activity. Runs on every interaction.
+    pub fn refresh(&mut self, user_id: i64) -> Result<Option<SessionExpiry>, BotError> {
+        let Some(user) = self.authorized_users.users.get(&user_id) else {
+            return Ok(None);
+        };
+
+        let now = self.clock.unix_time()?;
+        // Entries without a parsable time expire right away rather than never
+        let authorized_at = user.authorized_at.parse::<u64>().unwrap_or(0);
+        let last_seen = user.last_seen.unwrap_or(authorized_at);
+
+        let expiry = if self
+            .max_age
+            .is_some_and(|max_age| now.saturating_sub(authorized_at) >= max_age.as_secs())
+        {
+            Some(SessionExpiry::MaxAge)
+        } else if self
+            .idle_timeout
+            .is_some_and(|idle_timeout| now.saturating_sub(last_seen) >= idle_timeout.as_secs())
+        {
+            Some(SessionExpiry::Idle)
+        } else {
+            None
+        };
+
+        match expiry {
+            Some(expiry) => {
+                self.logout(user_id)?;
+                Ok(Some(expiry))
+            }
+            None => {
+                self.touch(user_id)?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Ends the authorization of the user. Their role and TOTP enrollment stay,
+    /// so signing in again with an access code or `/auth <totp>` restores them.
+    pub fn logout(&mut self, user_id: i64) -> Result<(), BotError> {
+        self.totp_verified_at.remove(&user_id);
+        if let Some(user) = self.authorized_users.users.remove(&user_id) {
+            self.authorized_users.signed_out_roles.insert(user_id, user.role);
+            self.save_authorized_users()?;
+        }
+        Ok(())
+    }
+
+    /// Records activity of the user. Only written to disk every so often, since
+    /// this runs on every command.
+    pub fn touch(&mut self, user_id: i64) -> Result<(), BotError> {
+        let now = self.clock.unix_time()?;
+
+        if let Some(user) = self.authorized_users.users.get_mut(&user_id) {
+            user.last_seen = Some(now);
+
+            let instant = self.clock.instant();
+            if instant.duration_since(self.last_seen_saved_at) >= LAST_SEEN_SAVE_INTERVAL {
+                self.last_seen_saved_at = instant;
+                self.save_authorized_users()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Also works for users who are signed out, so they lose their kept role.
+    pub fn revoke(&mut self, user_id: i64) -> Result<(), BotError> {
+        let role = self
+            .get_role(user_id)
+            .or_else(|| self.authorized_users.signed_out_roles.get(&user_id).copied())
+            .ok_or_else(|| BotError::AuthError(format!("User {} is not authorized", user_id)))?;
+
+        if role == Role::Admin && self.count_admins() == 1 {
+            return Err(BotError::AuthError("Cannot revoke the last admin".to_string()));
+        }
+
+        // The TOTP secret would let them right back in
+        self.authorized_users.users.remove(&user_id);
+        self.authorized_users.signed_out_roles.remove(&user_id);
+        self.authorized_users.totp.remove(&user_id);
+        self.totp_verified_at.remove(&user_id);
+        self.save_authorized_users()
+    }
+
+    pub fn set_role(&mut self, user_id: i64, role: Role) -> Result<(), BotError> {
+        let current_role = self
+            .get_role(user_id)
+            .ok_or_else(|| BotError::AuthError(format!("User {} is not authorized", user_id)))?;
+
+        if current_role == Role::Admin && role != Role::Admin && self.count_admins() == 1 {
+            return Err(BotError::AuthError("Cannot demote the last admin".to_string()));
+        }
+
+        if let Some(user) = self.authorized_users.users.get_mut(&user_id) {
+            user.role = role;
+        }
+        if let Some(enrollment) = self.authorized_users.totp.get_mut(&user_id) {
+            enrollment.role = role;
+        }
+        self.save_authorized_users()
+    }
+
     pub fn get_authorized_users(&self) -> &HashMap<i64, UserInfo> {
         &self.authorized_users.users
     }
-}
\ No newline at end of file
+
+    /// Roles kept for users who signed out or whose authorization expired.
+    pub fn get_signed_out_roles(&self) -> &HashMap<i64, Role> {
+        &self.authorized_users.signed_out_roles
+    }
+
+    /// Roles of the users enrolled for TOTP, without their secrets.
+    pub fn get_totp_roles(&self) -> HashMap<i64, Role> {
+        self.authorized_users
+            .totp
+            .iter()
+            .map(|(user_id, enrollment)| (*user_id, enrollment.role))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// A config with an auth file of its own in the temp directory.
+    fn config(settings: serde_json::Value) -> Config {
+        let auth_file_path =
+            std::env::temp_dir().join(format!("telebash-auth-{}.json", uuid::Uuid::new_v4()));
+        let mut config = serde_json::json!({
+            "telegram_token": "",
+            "auth_file_path": auth_file_path,
+            "log_file_path": "",
+            "working_directory": ".",
+        });
+        config
+            .as_object_mut()
+            .unwrap()
+            .extend(settings.as_object().unwrap().clone());
+
+        serde_json::from_value(config).unwrap()
+    }
+
+    fn manager(settings: serde_json::Value) -> (AuthManager, PathBuf) {
+        let config = config(settings);
+        (AuthManager::new(&config).unwrap(), PathBuf::from(config.auth_file_path))
+    }
+
+    #[test]
+    fn sensitive_commands_are_found_in_compound_commands_and_wrappers() {
+        let (manager, _) = manager(serde_json::json!({
+            "totp_sensitive_patterns": [r"^sudo\b"],
+        }));
+
+        for command in [
+            "sudo ls",
+            "ls; 'sudo' ls",
+            "env sudo ls",
+            "if :; then sudo rm -rf /; fi",
+            "for f in *; do sudo rm $f; done",
+            "timeout 60 sudo reboot",
+            "setsid sudo ls",
+            "sh -c 'sudo ls'",
+            "eval sudo ls",
+        ] {
+            assert!(manager.is_sensitive(command), "{} is not sensitive", command);
+        }
+
+        for command in ["ls", "echo sudo", "timeout 60 ls", "sh -c 'echo sudo'"] {
+            assert!(!manager.is_sensitive(command), "{} is sensitive", command);
+        }
+    }
+
+    fn remove_auth_file(path: PathBuf) {
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn access_codes_expire_after_their_ttl() {
+        let (mut manager, path) = manager(serde_json::json!({ "access_code_ttl_secs": 300 }));
+
+        let request = manager.generate_access_code(1).unwrap();
+        manager.clock.advance(Duration::from_secs(300));
+        assert!(matches!(
+            manager.verify_access_code(&request.code, 1, None).unwrap(),
+            VerifyStatus::Expired
+        ));
+
+        let request = manager.generate_access_code(1).unwrap();
+        manager.clock.advance(Duration::from_secs(299));
+        assert!(matches!(
+            manager.verify_access_code(&request.code, 1, None).unwrap(),
+            VerifyStatus::Authorized(Role::Admin)
+        ));
+        remove_auth_file(path);
+    }
+
+    #[test]
+    fn new_requests_replace_the_previous_code() {
+        let (mut manager, path) = manager(serde_json::json!({}));
+
+        let first = manager.generate_access_code(1).unwrap();
+        let second = manager.generate_access_code(1).unwrap();
+        assert!(first.notify_admins);
+        assert!(!second.notify_admins);
+        manager.clock.advance(ADMIN_NOTIFICATION_INTERVAL);
+        let third = manager.generate_access_code(1).unwrap();
+        assert!(third.notify_admins);
+
+        for old in [&first.code, &second.code] {
+            if *old != third.code {
+                assert!(!matches!(
+                    manager.verify_access_code(old, 1, None).unwrap(),
+                    VerifyStatus::Authorized(_)
+                ));
+            }
+        }
+        assert!(matches!(
+            manager.verify_access_code(&third.code, 1, None).unwrap(),
+            VerifyStatus::Authorized(_)
+        ));
+        remove_auth_file(path);
+    }
+
+    #[test]
+    fn codes_are_burned_after_too_many_wrong_attempts() {
+        let (mut manager, path) = manager(serde_json::json!({
+            "access_code_max_attempts": 3,
+            "auth_max_failures": 10,
+        }));
+
+        let request = manager.generate_access_code(1).unwrap();
+        for expected in [Some(2), Some(1), None] {
+            match manager.verify_access_code("000000", 1, None).unwrap() {
+                VerifyStatus::Invalid { attempts_left } => assert_eq!(attempts_left, expected),
+                _ => panic!("expected an invalid code"),
+            }
+        }
+        assert!(matches!(
+            manager.verify_access_code(&request.code, 1, None).unwrap(),
+            VerifyStatus::Invalid { attempts_left: None }
+        ));
+        remove_auth_file(path);
+    }
+
+    #[test]
+    fn lockout_doubles_with_every_failure_past_the_limit() {
+        let (mut manager, path) = manager(serde_json::json!({
+            "auth_max_failures": 2,
+            "auth_lockout_secs": 60,
+        }));
+
+        assert!(matches!(
+            manager.verify_access_code("000000", 1, None).unwrap(),
+            VerifyStatus::Invalid { .. }
+        ));
+        for lockout in [60, 120, 240] {
+            match manager.verify_access_code("000000", 1, None).unwrap() {
+                VerifyStatus::LockedOut(duration) => assert_eq!(duration.as_secs(), lockout),
+                _ => panic!("expected a lockout of {}s", lockout),
+            }
+            assert!(manager.generate_access_code(1).is_err());
+            assert!(matches!(
+                manager.verify_access_code("000000", 1, None).unwrap(),
+                VerifyStatus::LockedOut(_)
+            ));
+            manager.clock.advance(Duration::from_secs(lockout));
+        }
+        assert!(manager.generate_access_code(1).is_ok());
+        remove_auth_file(path);
+    }
+
+    #[test]
+    fn expired_codes_and_old_failures_are_pruned() {
+        let (mut manager, path) = manager(serde_json::json!({ "access_code_ttl_secs": 300 }));
+
+        for user_id in 1..=100 {
+            manager.generate_access_code(user_id).unwrap();
+            manager.verify_access_code("000000", user_id + 1000, None).unwrap();
+        }
+        manager.clock.advance(Duration::from_secs(300));
+        manager.generate_access_code(1).unwrap();
+        assert_eq!(manager.access_codes.len(), 1);
+        assert_eq!(manager.failed_attempts.len(), 100);
+
+        manager.clock.advance(FAILURE_MEMORY);
+        manager.verify_access_code("000000", 1, None).unwrap();
+        assert_eq!(manager.failed_attempts.len(), 1);
+        remove_auth_file(path);
+    }
+
+    #[test]
+    fn invites_survive_a_restart_and_are_used_once() {
+        let config = config(serde_json::json!({}));
+        let code = AuthManager::new(&config).unwrap().create_invite(Role::Operator).unwrap();
+
+        let mut manager = AuthManager::new(&config).unwrap();
+
+        assert!(matches!(
+            manager.verify_access_code(&code, 1, None).unwrap(),
+            VerifyStatus::Authorized(Role::Operator)
+        ));
+        assert!(!matches!(
+            manager.verify_access_code(&code, 2, None).unwrap(),
+            VerifyStatus::Authorized(_)
+        ));
+        assert!(AuthManager::new(&config).unwrap().authorized_users.invites.is_empty());
+        remove_auth_file(PathBuf::from(config.auth_file_path));
+    }
+
+    #[test]
+    fn expired_invites_are_refused() {
+        let (mut manager, path) = manager(serde_json::json!({}));
+        let code = manager.create_invite(Role::Viewer).unwrap();
+
+        manager.clock.advance(INVITE_TTL);
+        assert!(matches!(
+            manager.verify_access_code(&code, 1, None).unwrap(),
+            VerifyStatus::Expired
+        ));
+        remove_auth_file(path);
+    }
+
+    fn authorize(manager: &mut AuthManager, user_id: i64) {
+        let request = manager.generate_access_code(user_id).unwrap();
+        assert!(matches!(
+            manager.verify_access_code(&request.code, user_id, None).unwrap(),
+            VerifyStatus::Authorized(_)
+        ));
+    }
+
+    #[test]
+    fn authorizations_expire_at_their_maximum_age() {
+        let (mut manager, path) = manager(serde_json::json!({
+            "auth_max_age_secs": 100,
+            "auth_idle_timeout_secs": 0,
+        }));
+        authorize(&mut manager, 1);
+
+        manager.clock.advance(Duration::from_secs(99));
+        assert!(manager.refresh(1).unwrap().is_none());
+        manager.clock.advance(Duration::from_secs(1));
+        assert!(matches!(manager.refresh(1).unwrap(), Some(SessionExpiry::MaxAge)));
+        assert!(!manager.is_authorized(1));
+        assert_eq!(manager.authorized_users.signed_out_roles.get(&1), Some(&Role::Admin));
+
+        // Signing in again restores the role and starts a new period
+        authorize(&mut manager, 1);
+        assert_eq!(manager.get_role(1), Some(Role::Admin));
+        assert!(manager.refresh(1).unwrap().is_none());
+        remove_auth_file(path);
+    }
+
+    #[test]
+    fn authorizations_expire_when_idle() {
+        let (mut manager, path) = manager(serde_json::json!({
+            "auth_max_age_secs": 0,
+            "auth_idle_timeout_secs": 50,
+        }));
+        authorize(&mut manager, 1);
+
+        for _ in 0..5 {
+            manager.clock.advance(Duration::from_secs(49));
+            assert!(manager.refresh(1).unwrap().is_none());
+        }
+        manager.clock.advance(Duration::from_secs(50));
+        assert!(matches!(manager.refresh(1).unwrap(), Some(SessionExpiry::Idle)));
+        assert!(!manager.is_authorized(1));
+        remove_auth_file(path);
+    }
+
+    #[test]
+    fn authorizations_without_limits_never_expire() {
+        let (mut manager, path) = manager(serde_json::json!({
+            "auth_max_age_secs": 0,
+            "auth_idle_timeout_secs": 0,
+        }));
+        authorize(&mut manager, 1);
+
+        manager.clock.advance(Duration::from_secs(365 * 24 * 3600));
+        assert!(manager.refresh(1).unwrap().is_none());
+        assert!(manager.is_authorized(1));
+        remove_auth_file(path);
+    }
+
+    #[test]
+    fn auth_file_is_private_and_replaced_whole() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let (mut manager, path) = manager(serde_json::json!({}));
+        fs::write(&path, "{\"users\": {}}").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        manager.enroll_totp(1, Role::Admin).unwrap();
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(!PathBuf::from(format!("{}.tmp", path.display())).exists());
+
+        let config = config(serde_json::json!({ "auth_file_path": path }));
+        assert!(AuthManager::new(&config).unwrap().has_totp(1));
+        remove_auth_file(path);
+    }
+}
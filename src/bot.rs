@@ -1,27 +1,97 @@
-use crate::auth_manager::AuthManager;
+use crate::ansi::{self, Span, Style, Terminal};
+use crate::attachment::Attachment;
+use crate::auth_manager::{AuthManager, SessionExpiry, VerifyStatus};
 use crate::commands::Command;
 use crate::errors::BotError;
+use crate::executor::{self, terminate_process_group, ExecResult, RunningCommand};
 use crate::file_manager::FileManager;
+use crate::formatting;
+use crate::job_manager::{JobManager, JobState};
 use crate::log_manager::LogManager;
-use crate::types::Config;
+use crate::policy::{Policy, PolicyDecision};
+use crate::pty_shell::{PtyShell, CTRL_C, CTRL_D};
+use crate::session_manager::{PendingCommand, PendingUpload, SessionManager, UserSession};
+use crate::totp;
+use crate::types::{AnsiMode, AuditEntry, Config, FileItem, FileKind, ListingOptions, Permission, Role};
+use chrono::{DateTime, Local};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
+use teloxide::net::Download;
 use teloxide::prelude::*;
-use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup};
+use teloxide::{ApiError, RequestError};
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup, InputFile, ParseMode};
 use teloxide::utils::command::BotCommands;
+use tokio::sync::mpsc::UnboundedReceiver;
 use tokio::sync::Mutex;
 
+// Leave room for the command line and status under Telegram's 4096 character limit
+const MAX_OUTPUT_CHARS: usize = 3500;
+const MAX_COMMAND_DISPLAY_CHARS: usize = 200;
+const MAX_LISTING_NAME_CHARS: usize = 64;
+// Keeps a page under both the 100 button and the 4096 character limits
+const MAX_LISTING_PAGE_SIZE: usize = 50;
+const MAX_LONG_LISTING_PAGE_SIZE: usize = 25;
+// A full page of long entries this size still leaves room for the directory and page lines
+const MAX_LONG_ENTRY_CHARS: usize = 140;
+const DEFAULT_AUDIT_ENTRIES: usize = 20;
+const MAX_AUDIT_ENTRIES: usize = 100;
+const SHELL_OUTPUT_BATCH_DELAY: Duration = Duration::from_millis(500);
+
+const HELP_ENTRIES: &[(&str, Permission)] = &[
+    ("/ls [-l] [-a] [-t|-S] - List directory contents", Permission::ViewFiles),
+    ("/cd <directory> - Change directory", Permission::ViewFiles),
+    ("/pwd - Print working directory", Permission::ViewFiles),
+    ("/history - Show executed commands", Permission::ViewFiles),
+    ("/download <filename> - Download file", Permission::Download),
+    ("Send a file to upload it into the current directory", Permission::Upload),
+    ("/exec <command> - Execute command", Permission::Exec),
+    ("/bg <command> - Run command in the background", Permission::Exec),
+    ("/jobs - List running and finished jobs", Permission::Exec),
+    ("/kill <id> - Stop a running command", Permission::Exec),
+    ("/env [name] - Show the environment commands start with", Permission::Shell),
+    ("/setenv <name> <value> - Set a variable for your commands", Permission::Shell),
+    ("/unsetenv <name> - Remove a variable for your commands", Permission::Shell),
+    ("/shell - Start an interactive shell", Permission::Shell),
+    ("/exit - Close the interactive shell", Permission::Shell),
+    ("/logout - End your authorization", Permission::ViewFiles),
+    ("/users - List authorized users", Permission::ManageUsers),
+    ("/audit [n] [user] - Show recent audit log entries", Permission::ViewAudit),
+    ("/revoke <user id> - Revoke access of a user", Permission::ManageUsers),
+    ("/promote <user id> - Give a user the next higher role", Permission::ManageUsers),
+    ("/demote <user id> - Give a user the next lower role", Permission::ManageUsers),
+    ("/invite [role] - Create a one-time access code", Permission::ManageUsers),
+    ("/totp <code> - Confirm sensitive commands for a while", Permission::ViewFiles),
+    ("/totp enroll <user id> [role] - Create a TOTP secret for a user", Permission::ManageUsers),
+    ("/totp disable <user id> - Remove the TOTP secret of a user", Permission::ManageUsers),
+];
+
+/// What a press on the `/ls` keyboard leads to, worked out under the session lock
+/// so the slow Telegram requests can happen after it is released.
+enum BrowseOutcome {
+    Answer(String),
+    Download { path: PathBuf, name: String },
+    Listing { response: String, reply_markup: InlineKeyboardMarkup, answer: String },
+}
+
 pub struct BotManager {
     bot: Bot,
     auth_manager: Arc<Mutex<AuthManager>>,
-    file_manager: Arc<Mutex<FileManager>>,
+    session_manager: Arc<Mutex<SessionManager>>,
+    job_manager: Arc<Mutex<JobManager>>,
     log_manager: Arc<LogManager>,
+    policy: Arc<Policy>,
+    config: Arc<Config>,
 }
 
 impl BotManager {
     pub fn new(
         config: &Config,
         auth_manager: AuthManager,
-        file_manager: FileManager,
+        session_manager: SessionManager,
         log_manager: LogManager,
     ) -> Result<Self, BotError> {
         let bot = Bot::new(&config.telegram_token);
@@ -30,23 +100,50 @@ impl BotManager {
         Ok(BotManager {
             bot,
             auth_manager: Arc::new(Mutex::new(auth_manager)),
-            file_manager: Arc::new(Mutex::new(file_manager)),
+            session_manager: Arc::new(Mutex::new(session_manager)),
+            job_manager: Arc::new(Mutex::new(JobManager::new())),
             log_manager: Arc::new(log_manager),
+            policy: Arc::new(Policy::new(&config.exec_policy)?),
+            config: Arc::new(config.clone()),
         })
     }
 
     pub async fn run(&self) -> Result<(), BotError> {
-        let handler = Update::filter_message().branch(
-            dptree::entry()
-                .filter_command::<Command>()
-                .endpoint(Self::handle_command),
-        );
+        #[cfg(unix)]
+        tokio::spawn(Self::reopen_logs_on_hangup(self.log_manager.clone()));
+
+        // Users are told apart by the chat id, which is only theirs in private chats
+        let handler = dptree::entry()
+            .branch(
+                Update::filter_message()
+                    .filter(|msg: Message| msg.chat.is_private())
+                    .branch(
+                        dptree::entry()
+                            .filter_command::<Command>()
+                            .endpoint(Self::handle_command),
+                    )
+                    .branch(
+                        dptree::filter(|msg: Message| Attachment::from_message(&msg).is_some())
+                            .endpoint(Self::handle_upload),
+                    )
+                    .branch(dptree::endpoint(Self::handle_text)),
+            )
+            .branch(
+                Update::filter_callback_query()
+                    .filter(|query: CallbackQuery| {
+                        query.message.as_ref().is_none_or(|message| message.chat.is_private())
+                    })
+                    .endpoint(Self::handle_callback),
+            );
 
         Dispatcher::builder(self.bot.clone(), handler)
             .dependencies(dptree::deps![
                 self.auth_manager.clone(),
-                self.file_manager.clone(),
-                self.log_manager.clone()
+                self.session_manager.clone(),
+                self.job_manager.clone(),
+                self.log_manager.clone(),
+                self.policy.clone(),
+                self.config.clone()
             ])
             .build()
             .dispatch()
@@ -55,44 +152,203 @@ impl BotManager {
         Ok(())
     }
 
+    #[cfg(unix)]
+    async fn reopen_logs_on_hangup(log_manager: Arc<LogManager>) {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(e) => {
+                let _ = log_manager.log(
+                    log::Level::Warn,
+                    &format!("Failed to listen for SIGHUP, logs won't be reopened: {}", e),
+                );
+                return;
+            }
+        };
+
+        while hangups.recv().await.is_some() {
+            match log_manager.reopen() {
+                Ok(()) => {
+                    let _ = log_manager.log(log::Level::Info, "Reopened log files on SIGHUP");
+                }
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
     async fn handle_command(
         bot: Bot,
         msg: Message,
         cmd: Command,
         auth_manager: Arc<Mutex<AuthManager>>,
-        file_manager: Arc<Mutex<FileManager>>,
+        session_manager: Arc<Mutex<SessionManager>>,
+        job_manager: Arc<Mutex<JobManager>>,
         log_manager: Arc<LogManager>,
+        policy: Arc<Policy>,
+        config: Arc<Config>,
     ) -> Result<(), BotError> {
         let user_id = msg.chat.id.0;
-        let username = msg.chat.username().map(|s| s.to_string());
+
+        match cmd {
+            Command::Help | Command::Auth(_) => {
+                log_manager.audit(&Self::command_audit_entry(&msg, &cmd))?;
+            }
+            _ => {}
+        }
 
         match cmd {
             Command::Help => {
-                Self::handle_help(bot, msg, &auth_manager).await?;
+                Self::handle_help(bot, msg, &auth_manager, &session_manager).await?;
             }
-            Command::AuthRequest => {
+            Command::Auth(code) if code.trim().is_empty() => {
                 Self::handle_auth(bot, msg, auth_manager, log_manager).await?;
             }
             Command::Auth(code) => {
                 Self::handle_auth_code(bot, msg, code, auth_manager, log_manager).await?;
             }
             _ => {
-                if auth_manager.lock().await.is_authorized(user_id) {
+                if let Some(expiry) =
+                    Self::refresh_authorization(&auth_manager, &session_manager, user_id).await?
+                {
+                    bot.send_message(msg.chat.id, Self::expiry_message(&expiry))
+                        .await
+                        .map_err(|e| BotError::TelegramError(e.to_string()))?;
+                    return Ok(());
+                }
+
+                let role = auth_manager.lock().await.get_role(user_id);
+                let is_permitted = role.is_some_and(|role| {
+                    cmd.required_permission()
+                        .is_none_or(|permission| role.has_permission(permission))
+                });
+
+                // /exec and /bg write their own entries once the outcome is known
+                let audit_policy = match (role, is_permitted) {
+                    (None, _) => Some("unauthorized".to_string()),
+                    (Some(role), false) => Some(format!("denied for role {}", role)),
+                    (Some(_), true) if matches!(cmd, Command::Exec(_) | Command::Bg(_)) => None,
+                    (Some(_), true) => Some("allowed".to_string()),
+                };
+                if let Some(policy) = audit_policy {
+                    let mut audit = Self::command_audit_entry(&msg, &cmd);
+                    audit.policy = Some(policy);
+                    if role.is_some() {
+                        audit.cwd = Some(
+                            session_manager
+                                .lock()
+                                .await
+                                .get_session(user_id)?
+                                .file_manager
+                                .get_current_directory()
+                                .to_path_buf(),
+                        );
+                    }
+                    log_manager.audit(&audit)?;
+                }
+
+                if let (Some(role), false) = (role, is_permitted) {
+                    bot.send_message(
+                        msg.chat.id,
+                        format!("⛔ Your role ({}) doesn't allow this command.", role),
+                    )
+                    .await
+                    .map_err(|e| BotError::TelegramError(e.to_string()))?;
+                } else if let Some(role) = role {
                     match cmd {
-                        Command::Ls => {
-                            Self::handle_ls(bot, msg, file_manager).await?;
+                        Command::Ls(args) => {
+                            Self::handle_ls(bot, msg, args, session_manager, config).await?;
                         }
                         Command::Cd(path) => {
-                            Self::handle_cd(bot, msg, path, file_manager).await?;
+                            Self::handle_cd(bot, msg, path, session_manager).await?;
                         }
                         Command::Download(filename) => {
-                            Self::handle_download(bot, msg, filename, file_manager).await?;
+                            Self::handle_download(bot, msg, filename, session_manager).await?;
                         }
                         Command::Exec(command) => {
-                            Self::handle_exec(bot, msg, command, file_manager).await?;
+                            Self::handle_exec(
+                                bot,
+                                msg,
+                                command,
+                                role,
+                                auth_manager,
+                                session_manager,
+                                job_manager,
+                                log_manager,
+                                policy,
+                                config,
+                            )
+                            .await?;
+                        }
+                        Command::Bg(command) => {
+                            Self::handle_bg(
+                                bot,
+                                msg,
+                                command,
+                                role,
+                                auth_manager,
+                                session_manager,
+                                job_manager,
+                                log_manager,
+                                policy,
+                                config,
+                            )
+                            .await?;
+                        }
+                        Command::Jobs => {
+                            Self::handle_jobs(bot, msg, job_manager).await?;
+                        }
+                        Command::Kill(job_id) => {
+                            Self::handle_kill(bot, msg, job_id, job_manager).await?;
                         }
                         Command::Pwd => {
-                            Self::handle_pwd(bot, msg, file_manager).await?;
+                            Self::handle_pwd(bot, msg, session_manager).await?;
+                        }
+                        Command::History => {
+                            Self::handle_history(bot, msg, session_manager).await?;
+                        }
+                        Command::Shell => {
+                            Self::handle_shell(bot, msg, auth_manager, session_manager, config).await?;
+                        }
+                        Command::Exit => {
+                            Self::handle_exit_shell(bot, msg, session_manager).await?;
+                        }
+                        Command::Env(name) => {
+                            Self::handle_env(bot, msg, name, session_manager, config).await?;
+                        }
+                        Command::Setenv(args) => {
+                            Self::handle_setenv(bot, msg, args, session_manager).await?;
+                        }
+                        Command::Unsetenv(name) => {
+                            Self::handle_unsetenv(bot, msg, name, session_manager).await?;
+                        }
+                        Command::Users => {
+                            Self::handle_users(bot, msg, auth_manager).await?;
+                        }
+                        Command::Revoke(target) => {
+                            Self::handle_revoke(bot, msg, target, auth_manager, session_manager, job_manager)
+                                .await?;
+                        }
+                        Command::Promote(target) => {
+                            Self::handle_change_role(bot, msg, target, true, auth_manager, session_manager, job_manager).await?;
+                        }
+                        Command::Demote(target) => {
+                            Self::handle_change_role(bot, msg, target, false, auth_manager, session_manager, job_manager).await?;
+                        }
+                        Command::Invite(role) => {
+                            Self::handle_invite(bot, msg, role, auth_manager, config).await?;
+                        }
+                        Command::Logout => {
+                            Self::handle_logout(bot, msg, auth_manager, session_manager, log_manager)
+                                .await?;
+                        }
+                        Command::Audit(args) => {
+                            Self::handle_audit(bot, msg, args, log_manager).await?;
+                        }
+                        Command::Totp(args) => {
+                            Self::handle_totp(bot, msg, args, role, auth_manager, log_manager, config)
+                                .await?;
                         }
                         _ => {}
                     }
@@ -107,353 +363,2907 @@ impl BotManager {
         Ok(())
     }
 
-    async fn handle_help(
-        bot: teloxide::Bot,
+    async fn handle_text(
+        bot: Bot,
         msg: Message,
-        auth_manager: &Arc<Mutex<AuthManager>>,
+        auth_manager: Arc<Mutex<AuthManager>>,
+        session_manager: Arc<Mutex<SessionManager>>,
+        log_manager: Arc<LogManager>,
     ) -> Result<(), BotError> {
         let user_id = msg.chat.id.0;
-        let is_authorized = auth_manager.lock().await.is_authorized(user_id);
-
-        let help_text = if is_authorized {
-            "Available commands:\n\
-            /help - Show this help\n\
-            /ls - List directory contents\n\
-            /cd <directory> - Change directory\n\
-            /download <filename> - Download file\n\
-            /exec <command> - Execute command\n\
-            /pwd - Print working directory"
-        } else {
-            "Available commands:\n\
-            /help - Show this help\n\
-            /auth - Authorize with access code"
+
+        if let Some(expiry) =
+            Self::refresh_authorization(&auth_manager, &session_manager, user_id).await?
+        {
+            bot.send_message(msg.chat.id, Self::expiry_message(&expiry))
+                .await
+                .map_err(|e| BotError::TelegramError(e.to_string()))?;
+            return Ok(());
+        }
+
+        if !auth_manager.lock().await.has_permission(user_id, Permission::Shell) {
+            return Ok(());
+        }
+
+        let Some(text) = msg.text() else {
+            return Ok(());
         };
 
-        bot.send_message(msg.chat.id, help_text)
+        // Answers to password prompts stay out of the audit log, only their size is kept
+        let reading_secret = session_manager
+            .lock()
             .await
-            .map_err(|e| BotError::TelegramError(e.to_string()))?;
+            .get_session(user_id)?
+            .active_shell()
+            .is_some_and(|shell| shell.is_reading_secret());
+        let arguments = if reading_secret {
+            format!("<not echoed, {} bytes>", text.len())
+        } else {
+            text.to_string()
+        };
+
+        // Lines typed into the shell are as sensitive as /exec commands
+        let mut audit = Self::audit_entry(&msg, "shell", &arguments);
+        for line in text.lines() {
+            if !Self::check_totp(&bot, &msg, line, &auth_manager).await? {
+                audit.policy = Some("awaiting TOTP confirmation".to_string());
+                return log_manager.audit(&audit);
+            }
+        }
+
+        let written = {
+            let mut session_manager = session_manager.lock().await;
+            match session_manager.get_session(user_id)?.active_shell() {
+                Some(shell) => {
+                    shell.write(format!("{}\n", text).as_bytes())?;
+                    true
+                }
+                None => false,
+            }
+        };
+
+        if written {
+            audit.policy = Some("allowed".to_string());
+            log_manager.audit(&audit)?;
+        } else {
+            bot.send_message(msg.chat.id, "ℹ️ No active shell. Use /shell to start one.")
+                .await
+                .map_err(|e| BotError::TelegramError(e.to_string()))?;
+        }
 
         Ok(())
     }
 
-    async fn handle_auth(
-        bot: teloxide::Bot,
-        msg: Message,
+    async fn handle_callback(
+        bot: Bot,
+        query: CallbackQuery,
         auth_manager: Arc<Mutex<AuthManager>>,
+        session_manager: Arc<Mutex<SessionManager>>,
+        job_manager: Arc<Mutex<JobManager>>,
         log_manager: Arc<LogManager>,
+        config: Arc<Config>,
     ) -> Result<(), BotError> {
-        let user_id = msg.chat.id.0;
-        let username = msg.chat.username().map(|s| s.to_string());
-
-        let mut auth_manager = auth_manager.lock().await;
-
-        if auth_manager.is_authorized(user_id) {
-            bot.send_message(msg.chat.id, "✅ You are already authorized.")
+        let Some(user_id) = Self::callback_user_id(&query) else {
+            bot.answer_callback_query(query.id)
+                .text("❌ Message is no longer available")
                 .await
                 .map_err(|e| BotError::TelegramError(e.to_string()))?;
             return Ok(());
-        }
+        };
+        let data = query.data.as_deref().unwrap_or_default();
 
-        let access_code = auth_manager.generate_access_code(user_id);
+        let required_permission = match data.split(':').next() {
+            Some("job") | Some("confirm") => Permission::Exec,
+            Some("upload") => Permission::Upload,
+            Some("shell") => Permission::Shell,
+            _ => Permission::ViewFiles,
+        };
 
-        log_manager.log(
-            log::Level::Info,
-            &format!(
-                "Access code generated for user {}: {}",
-                user_id, access_code
-            ),
-        )?;
+        let expiry = Self::refresh_authorization(&auth_manager, &session_manager, user_id).await?;
 
-        println!("Access code for user {}: {}", user_id, access_code);
+        let answer = if expiry.is_some() {
+            "⌛ Your session expired. Use /auth to sign in again.".to_string()
+        } else if !auth_manager.lock().await.is_authorized(user_id) {
+            "❌ Unauthorized".to_string()
+        } else if !auth_manager
+            .lock()
+            .await
+            .has_permission(user_id, required_permission)
+        {
+            "⛔ Your role doesn't allow this action".to_string()
+        } else if let Some(job_id) = data.strip_prefix("job:stop:") {
+            let mut audit = Self::callback_audit_entry(&query, "stop", job_id);
+            audit.policy = Some("allowed".to_string());
+            log_manager.audit(&audit)?;
+            Self::stop_job(&job_manager, user_id, job_id).await
+        } else if let Some(pending_id) = data.strip_prefix("confirm:run:") {
+            Self::confirm_command(
+                &bot,
+                &query,
+                &session_manager,
+                &job_manager,
+                &log_manager,
+                &config,
+                user_id,
+                pending_id,
+                true,
+            )
+            .await?
+        } else if let Some(pending_id) = data.strip_prefix("confirm:cancel:") {
+            Self::confirm_command(
+                &bot,
+                &query,
+                &session_manager,
+                &job_manager,
+                &log_manager,
+                &config,
+                user_id,
+                pending_id,
+                false,
+            )
+            .await?
+        } else if let Some(action) = data.strip_prefix("ls:") {
+            Self::browse(&bot, &query, &session_manager, &log_manager, user_id, action, &config)
+                .await?
+        } else if let Some(upload_id) = data.strip_prefix("upload:overwrite:") {
+            Self::confirm_upload(
+                &bot,
+                &query,
+                &session_manager,
+                &log_manager,
+                user_id,
+                upload_id,
+                true,
+            )
+            .await?
+        } else if let Some(upload_id) = data.strip_prefix("upload:cancel:") {
+            Self::confirm_upload(
+                &bot,
+                &query,
+                &session_manager,
+                &log_manager,
+                user_id,
+                upload_id,
+                false,
+            )
+            .await?
+        } else {
+            match data {
+                "shell:ctrl_c" => Self::write_to_shell(&session_manager, user_id, CTRL_C).await?,
+                "shell:ctrl_d" => Self::write_to_shell(&session_manager, user_id, CTRL_D).await?,
+                _ => "❌ Unknown action",
+            }
+            .to_string()
+        };
 
-        bot.send_message(
-            msg.chat.id,
-            "🔑 Please enter the access code displayed in the console.",
-        )
-        .await
-        .map_err(|e| BotError::TelegramError(e.to_string()))?;
+        bot.answer_callback_query(query.id)
+            .text(answer)
+            .await
+            .map_err(|e| BotError::TelegramError(e.to_string()))?;
 
         Ok(())
     }
 
-    async fn handle_auth_code(
-        bot: teloxide::Bot,
+    async fn handle_upload(
+        bot: Bot,
         msg: Message,
-        code: String,
         auth_manager: Arc<Mutex<AuthManager>>,
+        session_manager: Arc<Mutex<SessionManager>>,
         log_manager: Arc<LogManager>,
+        config: Arc<Config>,
     ) -> Result<(), BotError> {
         let user_id = msg.chat.id.0;
-        let username = msg.chat.username().map(|s| s.to_string());
-
-        let mut auth_manager = auth_manager.lock().await;
 
-        if auth_manager.is_authorized(user_id) {
-            bot.send_message(msg.chat.id, "✅ You are already authorized.")
+        if let Some(expiry) =
+            Self::refresh_authorization(&auth_manager, &session_manager, user_id).await?
+        {
+            bot.send_message(msg.chat.id, Self::expiry_message(&expiry))
                 .await
                 .map_err(|e| BotError::TelegramError(e.to_string()))?;
             return Ok(());
         }
 
-        let is_verified = match auth_manager.verify_access_code(&code, user_id, username) {
-            Ok(_) => {}
-            Err(_) => {}
-        };
-
-        // log_manager.log(
-        //     log::Level::Info,
-        //     &format!(
-        //         "Access code generated for user {}: {}",
-        //         user_id, access_code
-        //     ),
-        // )?;
-        //
-        // println!("Access code for user {}: {}", user_id, access_code);
-        //
-        // bot.send_message(
-        //     msg.chat.id,
-        //     "🔑 Please enter the access code displayed in the console.",
-        // )
-        //     .await
-        //     .map_err(|e| BotError::TelegramError(e.to_string()))?;
-
-        Ok(())
-    }
-
-    fn escape_text(text: &str) -> String {
-        text.replace('.', "\\.")
-            .replace('!', "\\!")
-            .replace('-', "\\-")
-            .replace('(', "\\(")
-            .replace(')', "\\)")
-            .replace('+', "\\+")
-            .replace('=', "\\=")
-            .replace('{', "\\{")
-            .replace('}', "\\}")
-            .replace('[', "\\[")
-            .replace(']', "\\]")
-            .replace('>', "\\>")
-            .replace('#', "\\#")
-    }
-
-    async fn handle_ls(
-        bot: Bot,
-        msg: Message,
-        file_manager: Arc<Mutex<FileManager>>,
-    ) -> Result<(), BotError> {
-        let file_manager = file_manager.lock().await;
-        let items = file_manager.list_directory()?;
+        let auth_manager = auth_manager.lock().await;
+        let response = if !auth_manager.is_authorized(user_id) {
+            Some("❌ Unauthorized. Use /auth to get access.")
+        } else if !auth_manager.has_permission(user_id, Permission::Upload) {
+            Some("⛔ Your role doesn't allow uploads.")
+        } else {
+            None
+        };
+        drop(auth_manager);
 
-        if items.is_empty() {
-            bot.send_message(msg.chat.id, "📁 Directory is empty")
+        if let Some(response) = response {
+            bot.send_message(msg.chat.id, response)
                 .await
                 .map_err(|e| BotError::TelegramError(e.to_string()))?;
             return Ok(());
         }
 
-        // let mut response = String::new();
-        // response.push_str("📁 Directory contents:\n\n");
-        //
-        // for item in items {
-        //     let icon = if item.is_directory { "📁" } else { "📄" };
-        //     let command = if item.is_directory {
-        //         format!("cd {}", item.name)
-        //     } else {
-        //         format!("download {}", item.name)
-        //     };
-        //
-        //     response.push_str(&format!("{} {} `/{}`\n", icon, item.name, command));
-        // }
-        //
-        // response = Self::escape_text(response.as_str());
-        //
-        // bot.send_message(msg.chat.id, response)
-        //     .parse_mode(teloxide::types::ParseMode::MarkdownV2)
-        //     .await
-        //     .map_err(|e| BotError::TelegramError(e.to_string()))?;
-
-        let mut response = String::new();
-        let mut keyboard = Vec::new();
-        let mut current_row = Vec::new();
+        let Some(attachment) = Attachment::from_message(&msg) else {
+            return Ok(());
+        };
 
-        for item in items {
-            let icon = if item.is_directory { "📁" } else { "📄" };
-            let line = format!("{} {}\n", icon, item.name);
-            response.push_str(&line);
+        if u64::from(attachment.size) > config.max_upload_size_bytes {
+            bot.send_message(
+                msg.chat.id,
+                format!(
+                    "❌ File is too large ({} bytes, limit is {} bytes)",
+                    attachment.size, config.max_upload_size_bytes
+                ),
+            )
+            .await
+            .map_err(|e| BotError::TelegramError(e.to_string()))?;
+            return Ok(());
+        }
 
-            let button_text = if item.is_directory {
-                format!("📁 {}", item.name)
-            } else {
-                format!("📄 {}", item.name)
-            };
+        let target = {
+            let mut session_manager = session_manager.lock().await;
+            let session = session_manager.get_session(user_id)?;
 
-            let callback_data = if item.is_directory {
-                format!("/cd {}", item.name)
-            } else {
-                format!("/download {}", item.name)
-            };
+            match session.file_manager.get_upload_path(&attachment.file_name) {
+                Ok(target) if target.exists() => {
+                    let upload_id = session.add_pending_upload(PendingUpload {
+                        attachment: attachment.clone(),
+                        target: target.clone(),
+                    });
+                    drop(session_manager);
 
-            current_row.push(InlineKeyboardButton::callback(button_text, callback_data));
+                    let keyboard = InlineKeyboardMarkup::new(vec![vec![
+                        InlineKeyboardButton::callback(
+                            "✅ Overwrite",
+                            format!("upload:overwrite:{}", upload_id),
+                        ),
+                        InlineKeyboardButton::callback(
+                            "❌ Cancel",
+                            format!("upload:cancel:{}", upload_id),
+                        ),
+                    ]]);
 
-            if current_row.len() == 2 {
-                keyboard.push(current_row);
-                current_row = Vec::new();
+                    bot.send_message(
+                        msg.chat.id,
+                        format!("⚠️ {} already exists. Overwrite it?", target.display()),
+                    )
+                    .reply_markup(keyboard)
+                    .await
+                    .map_err(|e| BotError::TelegramError(e.to_string()))?;
+                    return Ok(());
+                }
+                Ok(target) => target,
+                Err(e) => {
+                    drop(session_manager);
+                    bot.send_message(msg.chat.id, format!("❌ Error: {}", e))
+                        .await
+                        .map_err(|e| BotError::TelegramError(e.to_string()))?;
+                    return Ok(());
+                }
             }
-        }
-
-        if !current_row.is_empty() {
-            keyboard.push(current_row);
-        }
+        };
 
-        let current_directory = file_manager.get_current_directory();
+        let result = Self::save_attachment(&bot, &attachment, &target).await;
 
-        // Add navigation buttons
-        if current_directory.parent().is_some() {
-            keyboard.push(vec![InlineKeyboardButton::callback(
-                "⬆️ ..",
-                "/cd ..".to_string(),
-            )]);
-        }
+        let mut audit = Self::audit_entry(&msg, "upload", &target.display().to_string());
+        audit.policy = Some(Self::upload_outcome(&log_manager, &target, &result, "allowed")?);
+        log_manager.audit(&audit)?;
 
-        let reply_markup = InlineKeyboardMarkup::new(keyboard);
+        let response = Self::upload_response(&target, result);
 
-        bot
-            .send_message(msg.chat.id, response)
-            .reply_markup(reply_markup)
-            .send()
+        bot.send_message(msg.chat.id, response)
             .await
-            .map_err(|e| {
-                BotError::TelegramError(format!("Failed to send message: {}", e))
-            })?;
+            .map_err(|e| BotError::TelegramError(e.to_string()))?;
 
         Ok(())
     }
 
-    async fn handle_cd(
-        bot: Bot,
-        msg: Message,
-        path: String,
-        file_manager: Arc<Mutex<FileManager>>,
-    ) -> Result<(), BotError> {
-        let mut file_manager = file_manager.lock().await;
+    async fn confirm_upload(
+        bot: &Bot,
+        query: &CallbackQuery,
+        session_manager: &Arc<Mutex<SessionManager>>,
+        log_manager: &LogManager,
+        user_id: i64,
+        upload_id: &str,
+        overwrite: bool,
+    ) -> Result<String, BotError> {
+        let pending = match upload_id.parse::<u64>() {
+            Ok(upload_id) => session_manager
+                .lock()
+                .await
+                .get_session(user_id)?
+                .take_pending_upload(upload_id),
+            Err(_) => None,
+        };
 
-        match file_manager.change_directory(&path) {
-            Ok(()) => {
-                let current_dir = file_manager.get_current_directory();
-                bot.send_message(
-                    msg.chat.id,
-                    format!("📁 Changed directory to: {}", current_dir.display()),
-                )
+        let Some(pending) = pending else {
+            return Ok("❌ Upload is no longer pending".to_string());
+        };
+
+        let response = if overwrite {
+            let result = Self::save_attachment(bot, &pending.attachment, &pending.target).await;
+
+            let target = pending.target.display().to_string();
+            let mut audit = Self::callback_audit_entry(query, "upload", &target);
+            audit.policy = Some(Self::upload_outcome(
+                log_manager,
+                &pending.target,
+                &result,
+                "overwrite confirmed",
+            )?);
+            log_manager.audit(&audit)?;
+
+            Self::upload_response(&pending.target, result)
+        } else {
+            "🚫 Upload cancelled".to_string()
+        };
+
+        if let Some(message) = &query.message {
+            bot.edit_message_text(message.chat.id, message.id, response.clone())
                 .await
                 .map_err(|e| BotError::TelegramError(e.to_string()))?;
-            }
-            Err(e) => {
-                bot.send_message(msg.chat.id, format!("❌ Error: {}", e))
-                    .await
-                    .map_err(|e| BotError::TelegramError(e.to_string()))?;
-            }
         }
 
-        Ok(())
+        Ok(response)
     }
 
-    async fn handle_download(
-        bot: teloxide::Bot,
-        msg: Message,
-        filename: String,
-        file_manager: Arc<Mutex<FileManager>>,
-    ) -> Result<(), BotError> {
-        let file_manager = file_manager.lock().await;
+    /// Downloads the attachment next to the target first, so a failed transfer
+    /// never leaves a truncated file in place of the original.
+    async fn save_attachment(bot: &Bot, attachment: &Attachment, target: &Path) -> Result<(), BotError> {
+        let mut partial_name = target.as_os_str().to_os_string();
+        partial_name.push(".part");
+        let partial = PathBuf::from(partial_name);
 
-        if !file_manager.file_exists(&filename) {
-            bot.send_message(msg.chat.id, "❌ File not found")
+        let result = async {
+            let file = bot
+                .get_file(&attachment.file_id)
                 .await
                 .map_err(|e| BotError::TelegramError(e.to_string()))?;
-            return Ok(());
-        }
 
-        if !file_manager.is_file(&filename) {
-            bot.send_message(msg.chat.id, "❌ Cannot download directories")
+            let mut destination = tokio::fs::File::from_std(FileManager::open_upload_file(&partial)?);
+
+            bot.download_file(&file.path, &mut destination)
                 .await
                 .map_err(|e| BotError::TelegramError(e.to_string()))?;
-            return Ok(());
+
+            tokio::fs::rename(&partial, target)
+                .await
+                .map_err(|e| BotError::FileError(format!("Failed to save file: {}", e)))
         }
+        .await;
 
-        let file_path = file_manager.get_file_path(&filename);
+        if result.is_err() {
+            let _ = tokio::fs::remove_file(&partial).await;
+        }
+        result
+    }
 
-        bot.send_document(msg.chat.id, teloxide::types::InputFile::file(&file_path))
-            .await
-            .map_err(|e| BotError::TelegramError(e.to_string()))?;
+    /// What the audit log records for an upload: how it was permitted, or why it failed.
+    fn upload_outcome(
+        log_manager: &LogManager,
+        target: &Path,
+        result: &Result<(), BotError>,
+        permitted: &str,
+    ) -> Result<String, BotError> {
+        match result {
+            Ok(()) => Ok(permitted.to_string()),
+            Err(e) => {
+                log_manager.log(
+                    log::Level::Error,
+                    &format!("Upload to {} failed: {}", target.display(), e),
+                )?;
+                Ok(format!("{}, failed: {}", permitted, e))
+            }
+        }
+    }
 
-        Ok(())
+    fn upload_response(target: &Path, result: Result<(), BotError>) -> String {
+        match result {
+            Ok(()) => format!("✅ Saved {}", target.display()),
+            Err(e) => format!("❌ Upload failed: {}", e),
+        }
     }
 
-    async fn handle_exec(
+    async fn handle_help(
         bot: teloxide::Bot,
         msg: Message,
-        command: String,
-        file_manager: Arc<Mutex<FileManager>>,
+        auth_manager: &Arc<Mutex<AuthManager>>,
+        session_manager: &Arc<Mutex<SessionManager>>,
     ) -> Result<(), BotError> {
-        let file_manager = file_manager.lock().await;
-        let current_dir = file_manager.get_current_directory();
+        let user_id = msg.chat.id.0;
 
-        // Basic command execution - in production, you'd want more security
-        let output = if cfg!(target_os = "windows") {
-            std::process::Command::new("cmd")
-                .args(["/C", &command])
-                .current_dir(current_dir)
-                .output()
-        } else {
-            std::process::Command::new("sh")
-                .args(["-c", &command])
-                .current_dir(current_dir)
-                .output()
-        };
+        if let Some(expiry) =
+            Self::refresh_authorization(auth_manager, session_manager, user_id).await?
+        {
+            bot.send_message(msg.chat.id, Self::expiry_message(&expiry))
+                .await
+                .map_err(|e| BotError::TelegramError(e.to_string()))?;
+            return Ok(());
+        }
 
-        match output {
-            Ok(output) => {
-                let response = if output.status.success() {
-                    format!(
-                        "✅ Command executed successfully:\n```\n{}\n```",
-                        String::from_utf8_lossy(&output.stdout)
-                    )
-                } else {
-                    format!(
-                        "❌ Command failed:\n```\n{}\n```",
-                        String::from_utf8_lossy(&output.stderr)
-                    )
-                };
+        let role = auth_manager.lock().await.get_role(user_id);
 
-                bot.send_message(msg.chat.id, response)
-                    .parse_mode(teloxide::types::ParseMode::MarkdownV2)
-                    .await
-                    .map_err(|e| BotError::TelegramError(e.to_string()))?;
-            }
-            Err(e) => {
-                bot.send_message(msg.chat.id, format!("❌ Failed to execute command: {}", e))
-                    .await
-                    .map_err(|e| BotError::TelegramError(e.to_string()))?;
+        let help_text = match role {
+            Some(role) => {
+                let mut help_text = format!(
+                    "Available commands (role: {}):\n/help - Show this help\n",
+                    role
+                );
+                for (line, permission) in HELP_ENTRIES {
+                    if role.has_permission(*permission) {
+                        help_text.push_str(line);
+                        help_text.push('\n');
+                    }
+                }
+                help_text
             }
-        }
+            None => "Available commands:\n\
+                /help - Show this help\n\
+                /auth - Request an access code\n\
+                /auth <code> - Authorize with the access code"
+                .to_string(),
+        };
 
-        Ok(())
+        Self::send_text(&bot, msg.chat.id, &help_text).await
     }
 
-    async fn handle_pwd(
+    async fn handle_auth(
         bot: teloxide::Bot,
         msg: Message,
-        file_manager: Arc<Mutex<FileManager>>,
+        auth_manager: Arc<Mutex<AuthManager>>,
+        log_manager: Arc<LogManager>,
     ) -> Result<(), BotError> {
-        let file_manager = file_manager.lock().await;
-        let current_dir = file_manager.get_current_directory();
+        let user_id = msg.chat.id.0;
+        let requester = Self::describe_user(user_id, msg.chat.username());
 
-        bot.send_message(
-            msg.chat.id,
-            format!("📁 Current directory: {}", current_dir.display()),
-        )
+        let mut auth_manager = auth_manager.lock().await;
+
+        if auth_manager.is_authorized(user_id) {
+            drop(auth_manager);
+            bot.send_message(msg.chat.id, "✅ You are already authorized.")
+                .await
+                .map_err(|e| BotError::TelegramError(e.to_string()))?;
+            return Ok(());
+        }
+
+        let request = match auth_manager.generate_access_code(user_id) {
+            Ok(request) => request,
+            Err(e) => {
+                drop(auth_manager);
+                log_manager.log(
+                    log::Level::Warn,
+                    &format!("Access code request by {} refused: {}", requester, e),
+                )?;
+                bot.send_message(msg.chat.id, format!("⏳ {}", e))
+                    .await
+                    .map_err(|e| BotError::TelegramError(e.to_string()))?;
+                return Ok(());
+            }
+        };
+
+        let admins: Vec<i64> = if request.notify_admins {
+            auth_manager
+                .get_authorized_users()
+                .values()
+                .filter(|user| user.role == Role::Admin)
+                .map(|user| user.user_id)
+                .collect()
+        } else {
+            Vec::new()
+        };
+        drop(auth_manager);
+
+        log_manager.log(
+            log::Level::Info,
+            &format!("Access code generated for {}", requester),
+        )?;
+
+        println!("Access code for {}: {}", requester, request.code);
+
+        let mut notified = 0;
+        for admin_id in admins {
+            let delivered = bot
+                .send_message(
+                    ChatId(admin_id),
+                    format!(
+                        "🔑 {} requests access. If you trust them, forward this:\n\n/auth {}",
+                        requester, request.code
+                    ),
+                )
+                .await;
+
+            match delivered {
+                Ok(_) => notified += 1,
+                Err(e) => log_manager.log(
+                    log::Level::Warn,
+                    &format!("Failed to deliver access code to admin {}: {}", admin_id, e),
+                )?,
+            }
+        }
+
+        let response = if !request.notify_admins {
+            "🔑 A new access code was printed in the bot's console, any earlier one no longer works. \
+            The administrators were notified of your request moments ago, so it wasn't sent to them. \
+            Send it back with /auth <code>."
+        } else if notified == 0 {
+            "🔑 An access code was printed in the bot's console. Send it back with /auth <code>."
+        } else if notified == 1 {
+            "🔑 An access code was sent to the administrator and printed in the bot's console. \
+            Send it back with /auth <code>."
+        } else {
+            "🔑 An access code was sent to the administrators and printed in the bot's console. \
+            Send it back with /auth <code>."
+        };
+        bot.send_message(msg.chat.id, response)
+            .await
+            .map_err(|e| BotError::TelegramError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    async fn handle_auth_code(
+        bot: teloxide::Bot,
+        msg: Message,
+        code: String,
+        auth_manager: Arc<Mutex<AuthManager>>,
+        log_manager: Arc<LogManager>,
+    ) -> Result<(), BotError> {
+        let user_id = msg.chat.id.0;
+        let username = msg.chat.username().map(|s| s.to_string());
+        let requester = Self::describe_user(user_id, msg.chat.username());
+
+        let status = {
+            let mut auth_manager = auth_manager.lock().await;
+
+            if auth_manager.is_authorized(user_id) {
+                None
+            } else {
+                let status = auth_manager.verify_access_code(&code, user_id, username)?;
+                if matches!(status, VerifyStatus::Authorized(_)) {
+                    auth_manager.touch(user_id)?;
+                }
+                Some(status)
+            }
+        };
+
+        let (failure, response) = match status {
+            None => (None, "✅ You are already authorized.".to_string()),
+            Some(VerifyStatus::Authorized(role)) => {
+                log_manager.log(
+                    log::Level::Info,
+                    &format!("{} authorized with role {}", requester, role),
+                )?;
+                (
+                    None,
+                    format!("✅ Access granted, your role is {}. Send /help to get started.", role),
+                )
+            }
+            Some(VerifyStatus::Invalid { attempts_left: Some(attempts_left) }) => (
+                Some(format!("invalid code, {} attempts left", attempts_left)),
+                format!("❌ Wrong access code. {} attempts left.", attempts_left),
+            ),
+            Some(VerifyStatus::Invalid { attempts_left: None }) => (
+                Some("invalid code".to_string()),
+                "❌ Wrong access code. Use /auth to request a new one.".to_string(),
+            ),
+            Some(VerifyStatus::Expired) => (
+                Some("expired code".to_string()),
+                "⌛ This access code has expired. Use /auth to request a new one.".to_string(),
+            ),
+            Some(VerifyStatus::LockedOut(lockout)) => (
+                Some(format!("locked out for {}s", lockout.as_secs())),
+                format!("⏳ Too many failed attempts, try again in {}s.", lockout.as_secs().max(1)),
+            ),
+        };
+
+        if let Some(reason) = failure {
+            log_manager.log(
+                log::Level::Warn,
+                &format!("Failed access code attempt by {}: {}", requester, reason),
+            )?;
+        }
+
+        bot.send_message(msg.chat.id, response)
+            .await
+            .map_err(|e| BotError::TelegramError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Expires a stale authorization along with the user's session, or records the
+    /// activity otherwise.
+    async fn refresh_authorization(
+        auth_manager: &Mutex<AuthManager>,
+        session_manager: &Mutex<SessionManager>,
+        user_id: i64,
+    ) -> Result<Option<SessionExpiry>, BotError> {
+        let expiry = auth_manager.lock().await.refresh(user_id)?;
+
+        if expiry.is_some() {
+            session_manager.lock().await.remove_session(user_id);
+        }
+
+        Ok(expiry)
+    }
+
+    fn expiry_message(expiry: &SessionExpiry) -> String {
+        format!("⌛ Your session expired ({}). Use /auth to sign in again.", expiry)
+    }
+
+    async fn handle_logout(
+        bot: teloxide::Bot,
+        msg: Message,
+        auth_manager: Arc<Mutex<AuthManager>>,
+        session_manager: Arc<Mutex<SessionManager>>,
+        log_manager: Arc<LogManager>,
+    ) -> Result<(), BotError> {
+        let user_id = msg.chat.id.0;
+
+        auth_manager.lock().await.logout(user_id)?;
+        session_manager.lock().await.remove_session(user_id);
+
+        log_manager.log(
+            log::Level::Info,
+            &format!(
+                "{} logged out",
+                Self::describe_user(user_id, msg.chat.username())
+            ),
+        )?;
+
+        bot.send_message(msg.chat.id, "👋 Logged out. Use /auth to sign in again.")
+            .await
+            .map_err(|e| BotError::TelegramError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    fn audit_entry(msg: &Message, command: &str, arguments: &str) -> AuditEntry {
+        let username = msg.from().and_then(|user| user.username.as_deref());
+        AuditEntry::new(msg.chat.id.0, username, msg.chat.id.0, command, arguments)
+    }
+
+    /// Entry for a button press, attributed like the messages of the same chat.
+    fn callback_audit_entry(query: &CallbackQuery, command: &str, arguments: &str) -> AuditEntry {
+        let user_id = Self::callback_user_id(query).unwrap_or(query.from.id.0 as i64);
+        AuditEntry::new(user_id, query.from.username.as_deref(), user_id, command, arguments)
+    }
+
+    /// A press acts for the chat its keyboard was sent to, the same id messages
+    /// from there are handled as.
+    fn callback_user_id(query: &CallbackQuery) -> Option<i64> {
+        query.message.as_ref().map(|message| message.chat.id.0)
+    }
+
+    /// Entry for a command as typed, with codes and secrets left out.
+    fn command_audit_entry(msg: &Message, cmd: &Command) -> AuditEntry {
+        let text = msg.text().unwrap_or_default();
+        let (name, arguments) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        let name = name
+            .trim_start_matches('/')
+            .split('@')
+            .next()
+            .unwrap_or_default()
+            .to_lowercase();
+
+        let arguments = match cmd {
+            Command::Auth(_) | Command::Totp(_) if !arguments.trim().is_empty() => {
+                let mut words = arguments.split_whitespace();
+                match (words.next(), cmd) {
+                    // Enrolling and disabling carry only user ids and roles
+                    (Some(action @ ("enroll" | "disable")), Command::Totp(_)) => {
+                        format!("{} {}", action, words.collect::<Vec<_>>().join(" "))
+                    }
+                    _ => "<redacted>".to_string(),
+                }
+            }
+            // Values may well be tokens or passwords
+            Command::Setenv(args) => match Self::parse_setenv(args) {
+                Ok((name, _)) => format!("{} <redacted>", name),
+                Err(_) => arguments.trim().to_string(),
+            },
+            _ => arguments.trim().to_string(),
+        };
+
+        Self::audit_entry(msg, &name, &arguments)
+    }
+
+    async fn handle_audit(
+        bot: teloxide::Bot,
+        msg: Message,
+        args: String,
+        log_manager: Arc<LogManager>,
+    ) -> Result<(), BotError> {
+        let mut args = args.split_whitespace().peekable();
+        let limit = match args.peek().and_then(|arg| arg.parse::<usize>().ok()) {
+            Some(limit) => {
+                args.next();
+                limit.clamp(1, MAX_AUDIT_ENTRIES)
+            }
+            None => DEFAULT_AUDIT_ENTRIES,
+        };
+        let user = args.next().map(str::to_string);
+
+        // Rotated files may need decompressing, which shouldn't stall the runtime
+        let entries = tokio::task::spawn_blocking(move || {
+            log_manager.recent_audit_entries(limit, user.as_deref())
+        })
+        .await
+        .map_err(|e| BotError::LogError(format!("Failed to read audit log: {}", e)))??;
+
+        let response = if entries.is_empty() {
+            "📜 No audit entries".to_string()
+        } else {
+            let mut response = String::from("📜 Audit log:\n");
+            for entry in &entries {
+                response.push('\n');
+                response.push_str(&Self::format_audit_entry(entry));
+            }
+            response
+        };
+
+        Self::send_text(&bot, msg.chat.id, &response).await
+    }
+
+    fn format_audit_entry(entry: &AuditEntry) -> String {
+        let user = match &entry.username {
+            Some(username) => format!("@{}", username),
+            None => entry.user_id.to_string(),
+        };
+
+        let mut line = format!(
+            "{} {} /{} {}",
+            entry.timestamp.format("%m-%d %H:%M:%S"),
+            user,
+            entry.command,
+            Self::shorten_name(&entry.arguments)
+        );
+
+        if let Some(policy) = &entry.policy {
+            line.push_str(&format!(" [{}]", policy));
+        }
+        if let Some(exit_code) = entry.exit_code {
+            line.push_str(&format!(" → exit {}", exit_code));
+        }
+        if let Some(signal) = entry.signal {
+            line.push_str(&format!(" → signal {}", signal));
+        }
+        if let Some(duration_ms) = entry.duration_ms {
+            line.push_str(&format!(" in {:.1}s", duration_ms as f64 / 1000.0));
+        }
+        if let Some(output_bytes) = entry.output_bytes {
+            line.push_str(&format!(", {} output", Self::format_size(output_bytes as u64)));
+        }
+
+        line.push('\n');
+        line
+    }
+
+    fn describe_user(user_id: i64, username: Option<&str>) -> String {
+        match username {
+            Some(username) => format!("user {} (@{})", user_id, username),
+            None => format!("user {}", user_id),
+        }
+    }
+
+    fn shorten_name(name: &str) -> String {
+        if name.chars().count() <= MAX_LISTING_NAME_CHARS {
+            return name.to_string();
+        }
+
+        let mut shortened: String = name.chars().take(MAX_LISTING_NAME_CHARS - 1).collect();
+        shortened.push('…');
+        shortened
+    }
+
+    fn file_icon(item: &FileItem) -> &'static str {
+        match item.kind {
+            FileKind::Directory => "📁",
+            FileKind::Symlink => "🔗",
+            FileKind::Socket => "🔌",
+            FileKind::Fifo | FileKind::BlockDevice | FileKind::CharDevice => "📟",
+            FileKind::File if item.is_executable() => "⚙️",
+            FileKind::File if item.is_hidden() => "👻",
+            FileKind::File => "📄",
+        }
+    }
+
+    fn format_permissions(item: &FileItem) -> String {
+        let type_char = match item.kind {
+            FileKind::File => '-',
+            FileKind::Directory => 'd',
+            FileKind::Symlink => 'l',
+            FileKind::Socket => 's',
+            FileKind::Fifo => 'p',
+            FileKind::BlockDevice => 'b',
+            FileKind::CharDevice => 'c',
+        };
+
+        let mode = item.mode;
+        let bit = |mask: u32, c: char| if mode & mask != 0 { c } else { '-' };
+        let special = |exec_mask: u32, special_mask: u32, set: char, unset: char| {
+            match (mode & exec_mask != 0, mode & special_mask != 0) {
+                (true, true) => set,
+                (false, true) => unset,
+                (true, false) => 'x',
+                (false, false) => '-',
+            }
+        };
+
+        [
+            type_char,
+            bit(0o400, 'r'),
+            bit(0o200, 'w'),
+            special(0o100, 0o4000, 's', 'S'),
+            bit(0o040, 'r'),
+            bit(0o020, 'w'),
+            special(0o010, 0o2000, 's', 'S'),
+            bit(0o004, 'r'),
+            bit(0o002, 'w'),
+            special(0o001, 0o1000, 't', 'T'),
+        ]
+        .iter()
+        .collect()
+    }
+
+    fn format_size(size: u64) -> String {
+        const UNITS: [&str; 5] = ["K", "M", "G", "T", "P"];
+
+        if size < 1024 {
+            return size.to_string();
+        }
+
+        let mut value = size as f64;
+        let mut unit = "";
+        for next_unit in UNITS {
+            if value < 1024.0 {
+                break;
+            }
+            value /= 1024.0;
+            unit = next_unit;
+        }
+
+        format!("{:.1}{}", value, unit)
+    }
+
+    fn format_long_entry(item: &FileItem) -> String {
+        let modified = item
+            .modified
+            .map(|time| time.format("%b %e %H:%M").to_string())
+            .unwrap_or_else(|| "?".repeat(12));
+
+        let mut line = format!(
+            "{} {:<8} {:<8} {:>6} {} {} {}",
+            Self::format_permissions(item),
+            item.owner,
+            item.group,
+            Self::format_size(item.size),
+            modified,
+            Self::file_icon(item),
+            Self::shorten_name(&item.name),
+        );
+
+        if let Some(target) = &item.symlink_target {
+            line.push_str(" -> ");
+            line.push_str(&Self::shorten_name(&target.display().to_string()));
+        }
+
+        // Long owner or group names could still push it over
+        formatting::truncate(&line, MAX_LONG_ENTRY_CHARS)
+    }
+
+    /// Renders one page of the session's listing as HTML. Buttons carry the listing
+    /// generation and the entry index instead of the file name, which keeps callback
+    /// data within Telegram's 64 bytes.
+    fn build_listing(session: &UserSession, page: usize, page_size: usize) -> (String, InlineKeyboardMarkup) {
+        let entries = &session.browser_entries;
+        let options = &session.browser_options;
+        let generation = session.browser_generation;
+        let current_directory = session.file_manager.get_current_directory();
+
+        let max_page_size = if options.long {
+            MAX_LONG_LISTING_PAGE_SIZE
+        } else {
+            MAX_LISTING_PAGE_SIZE
+        };
+        let page_size = page_size.clamp(1, max_page_size);
+        let total_pages = entries.len().div_ceil(page_size).max(1);
+        let page = page.min(total_pages - 1);
+        let first = page * page_size;
+
+        let mut response = format!(
+            "📁 <b>{}</b>\n\n",
+            formatting::escape_html(&current_directory.display().to_string())
+        );
+        let mut lines = Vec::new();
+        let mut keyboard = Vec::new();
+        let mut current_row = Vec::new();
+
+        if entries.is_empty() {
+            response.push_str("Directory is empty\n");
+        }
+
+        for (index, item) in entries.iter().enumerate().skip(first).take(page_size) {
+            let icon = Self::file_icon(item);
+            let name = Self::shorten_name(&item.name);
+
+            lines.push(if options.long {
+                Self::format_long_entry(item)
+            } else {
+                format!("{} {}", icon, name)
+            });
+
+            current_row.push(InlineKeyboardButton::callback(
+                format!("{} {}", icon, name),
+                format!("ls:{}:o:{}", generation, index),
+            ));
+
+            if current_row.len() == 2 {
+                keyboard.push(current_row);
+                current_row = Vec::new();
+            }
+        }
+
+        if !lines.is_empty() {
+            let lines = formatting::escape_html(&lines.join("\n"));
+            if options.long {
+                response.push_str(&format!("<pre>{}</pre>\n", lines));
+            } else {
+                response.push_str(&format!("{}\n", lines));
+            }
+        }
+
+        if !current_row.is_empty() {
+            keyboard.push(current_row);
+        }
+
+        if total_pages > 1 {
+            response.push_str(&format!("\nPage {}/{}", page + 1, total_pages));
+
+            let mut navigation = Vec::new();
+            if page > 0 {
+                navigation.push(InlineKeyboardButton::callback(
+                    "◀️",
+                    format!("ls:{}:p:{}", generation, page - 1),
+                ));
+            }
+            navigation.push(InlineKeyboardButton::callback(
+                format!("{}/{}", page + 1, total_pages),
+                format!("ls:{}:p:{}", generation, page),
+            ));
+            if page + 1 < total_pages {
+                navigation.push(InlineKeyboardButton::callback(
+                    "▶️",
+                    format!("ls:{}:p:{}", generation, page + 1),
+                ));
+            }
+            keyboard.push(navigation);
+        }
+
+        // Add navigation buttons
+        if session.file_manager.can_go_up() {
+            keyboard.push(vec![InlineKeyboardButton::callback(
+                "⬆️ ..",
+                format!("ls:{}:up", generation),
+            )]);
+        }
+
+        (response, InlineKeyboardMarkup::new(keyboard))
+    }
+
+    async fn handle_ls(
+        bot: Bot,
+        msg: Message,
+        args: String,
+        session_manager: Arc<Mutex<SessionManager>>,
+        config: Arc<Config>,
+    ) -> Result<(), BotError> {
+        let user_id = msg.chat.id.0;
+
+        let options = match ListingOptions::parse(&args) {
+            Ok(options) => options,
+            Err(e) => {
+                bot.send_message(msg.chat.id, format!("❌ {}\nUsage: /ls [-l] [-a] [-t|-S]", e))
+                    .await
+                    .map_err(|e| BotError::TelegramError(e.to_string()))?;
+                return Ok(());
+            }
+        };
+
+        let (response, reply_markup) = {
+            let mut session_manager = session_manager.lock().await;
+            let session = session_manager.get_session(user_id)?;
+            session.browser_options = options;
+            let entries = session.file_manager.list_directory(&options)?;
+            session.set_browser_entries(entries);
+            Self::build_listing(session, 0, config.ls_page_size)
+        };
+
+        bot
+            .send_message(msg.chat.id, response)
+            .parse_mode(ParseMode::Html)
+            .reply_markup(reply_markup)
+            .send()
+            .await
+            .map_err(|e| {
+                BotError::TelegramError(format!("Failed to send message: {}", e))
+            })?;
+
+        Ok(())
+    }
+
+    /// Handles a press on the `/ls` keyboard: paging, entering directories and
+    /// downloading files, redrawing the listing in place.
+    async fn browse(
+        bot: &Bot,
+        query: &CallbackQuery,
+        session_manager: &Arc<Mutex<SessionManager>>,
+        log_manager: &LogManager,
+        user_id: i64,
+        action: &str,
+        config: &Config,
+    ) -> Result<String, BotError> {
+        let Some(message) = &query.message else {
+            return Ok("❌ Message is no longer available".to_string());
+        };
+
+        let outcome = {
+            let mut session_manager = session_manager.lock().await;
+            let session = session_manager.get_session(user_id)?;
+            Self::browse_session(session, query, log_manager, action, config.ls_page_size)?
+        };
+
+        match outcome {
+            BrowseOutcome::Answer(answer) => Ok(answer),
+            BrowseOutcome::Download { path, name } => {
+                bot.send_document(message.chat.id, InputFile::file(&path))
+                    .await
+                    .map_err(|e| BotError::TelegramError(e.to_string()))?;
+                Ok(format!("📄 {}", name))
+            }
+            BrowseOutcome::Listing { response, reply_markup, answer } => {
+                match bot
+                    .edit_message_text(message.chat.id, message.id, response)
+                    .parse_mode(ParseMode::Html)
+                    .reply_markup(reply_markup)
+                    .await
+                {
+                    // Going up from the filesystem root or pressing the page indicator changes nothing
+                    Ok(_) | Err(RequestError::Api(ApiError::MessageNotModified)) => {}
+                    Err(e) => return Err(BotError::TelegramError(e.to_string())),
+                }
+                Ok(answer)
+            }
+        }
+    }
+
+    fn browse_session(
+        session: &mut UserSession,
+        query: &CallbackQuery,
+        log_manager: &LogManager,
+        action: &str,
+        page_size: usize,
+    ) -> Result<BrowseOutcome, BotError> {
+        let outdated = || Ok(BrowseOutcome::Answer("❌ Listing is outdated, run /ls again".to_string()));
+
+        // Entries of an older listing, or of another directory, are gone
+        let (generation, action) = action.split_once(':').unwrap_or_default();
+        if generation.parse() != Ok(session.browser_generation) {
+            return outdated();
+        }
+
+        let (page, answer) = if let Some(page) = action.strip_prefix("p:") {
+            (page.parse().unwrap_or(0), String::new())
+        } else if action == "up" {
+            if let Err(e) = session.file_manager.change_directory("..") {
+                return Ok(BrowseOutcome::Answer(format!("❌ {}", e)));
+            }
+            let entries = session.file_manager.list_directory(&session.browser_options)?;
+            session.set_browser_entries(entries);
+            (0, format!("📁 {}", session.file_manager.get_current_directory().display()))
+        } else if let Some(index) = action.strip_prefix("o:") {
+            let Some(item) = index
+                .parse::<usize>()
+                .ok()
+                .and_then(|index| session.browser_entries.get(index))
+                .cloned()
+            else {
+                return outdated();
+            };
+
+            if !item.is_directory {
+                let file_path = match session.file_manager.resolve_path(&item.path) {
+                    Ok(file_path) if file_path.is_file() => file_path,
+                    Ok(_) => return Ok(BrowseOutcome::Answer("❌ File not found".to_string())),
+                    Err(e) => return Ok(BrowseOutcome::Answer(format!("❌ {}", e))),
+                };
+
+                let mut audit =
+                    Self::callback_audit_entry(query, "download", &file_path.display().to_string());
+                audit.policy = Some("allowed".to_string());
+                audit.cwd = Some(session.file_manager.get_current_directory().to_path_buf());
+                log_manager.audit(&audit)?;
+
+                return Ok(BrowseOutcome::Download {
+                    path: file_path,
+                    name: item.name,
+                });
+            }
+
+            if let Err(e) = session.file_manager.set_current_directory(&item.path) {
+                return Ok(BrowseOutcome::Answer(format!("❌ {}", e)));
+            }
+            let entries = session.file_manager.list_directory(&session.browser_options)?;
+            session.set_browser_entries(entries);
+            (0, format!("📁 {}", session.file_manager.get_current_directory().display()))
+        } else {
+            return Ok(BrowseOutcome::Answer("❌ Unknown action".to_string()));
+        };
+
+        let (response, reply_markup) = Self::build_listing(session, page, page_size);
+        Ok(BrowseOutcome::Listing {
+            response,
+            reply_markup,
+            answer,
+        })
+    }
+
+    async fn handle_cd(
+        bot: Bot,
+        msg: Message,
+        path: String,
+        session_manager: Arc<Mutex<SessionManager>>,
+    ) -> Result<(), BotError> {
+        let user_id = msg.chat.id.0;
+        let response = {
+            let mut session_manager = session_manager.lock().await;
+            let session = session_manager.get_session(user_id)?;
+
+            match session.file_manager.change_directory(&path) {
+                Ok(()) => {
+                    session.invalidate_listing();
+                    let current_dir = session.file_manager.get_current_directory();
+                    format!("📁 Changed directory to: {}", current_dir.display())
+                }
+                Err(e) => format!("❌ Error: {}", e),
+            }
+        };
+
+        bot.send_message(msg.chat.id, response)
+            .await
+            .map_err(|e| BotError::TelegramError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    async fn handle_download(
+        bot: teloxide::Bot,
+        msg: Message,
+        filename: String,
+        session_manager: Arc<Mutex<SessionManager>>,
+    ) -> Result<(), BotError> {
+        let user_id = msg.chat.id.0;
+        // Not held during the upload, which can take a while for large files
+        let file_path = {
+            let mut session_manager = session_manager.lock().await;
+            let file_manager = &session_manager.get_session(user_id)?.file_manager;
+            Self::get_download_path(file_manager, &filename)
+        };
+
+        match file_path {
+            Ok(file_path) => {
+                bot.send_document(msg.chat.id, InputFile::file(&file_path))
+                    .await
+                    .map_err(|e| BotError::TelegramError(e.to_string()))?;
+            }
+            Err(error) => {
+                bot.send_message(msg.chat.id, error)
+                    .await
+                    .map_err(|e| BotError::TelegramError(e.to_string()))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn get_download_path(file_manager: &FileManager, filename: &str) -> Result<PathBuf, String> {
+        let file_path = match file_manager.get_file_path(filename) {
+            Ok(file_path) => file_path,
+            Err(BotError::SandboxError(e)) => return Err(format!("❌ Access denied: {}", e)),
+            Err(_) => return Err("❌ File not found".to_string()),
+        };
+
+        if !file_path.is_file() {
+            return Err("❌ Cannot download directories".to_string());
+        }
+
+        Ok(file_path)
+    }
+
+    /// Sends plain text, split into as many messages as it takes.
+    async fn send_text(bot: &Bot, chat_id: ChatId, text: &str) -> Result<(), BotError> {
+        for part in formatting::split_message(text, formatting::MESSAGE_LIMIT) {
+            bot.send_message(chat_id, part)
+                .await
+                .map_err(|e| BotError::TelegramError(e.to_string()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Renders a command with its output as HTML, within one message. Also returns
+    /// whether output had to be shortened, in which case the full streams get attached.
+    fn render_exec_message(
+        command: &str,
+        result: &ExecResult,
+        status_line: &str,
+        config: &Config,
+    ) -> (String, bool) {
+        let mut text = format!(
+            "$ {}\n\n",
+            formatting::html_inline_code(&formatting::truncate(command, MAX_COMMAND_DISPLAY_CHARS))
+        );
+
+        let sections = if config.exec_interleave_output {
+            vec![(None, ansi::parse(&result.combined))]
+        } else {
+            vec![
+                (Some("stdout"), ansi::parse(&result.stdout)),
+                (Some("stderr"), ansi::parse(&result.stderr)),
+            ]
+        };
+        let sections: Vec<_> = sections
+            .into_iter()
+            .filter(|(_, output)| !output.is_empty())
+            .collect();
+
+        // The shorter stream keeps up to half of the room, the other one gets the rest
+        let budget = config.output_attachment_threshold_chars.clamp(100, MAX_OUTPUT_CHARS);
+        let shortest = sections
+            .iter()
+            .map(|(_, output)| ansi::text_len(output))
+            .min()
+            .unwrap_or(0);
+        let small_share = shortest.min(budget / 2);
+
+        let mut shortened = false;
+        for (label, output) in &sections {
+            let len = ansi::text_len(output);
+            let share = if sections.len() > 1 && len == shortest {
+                small_share
+            } else {
+                budget - if sections.len() > 1 { small_share } else { 0 }
+            };
+
+            let (preview, cut) = Self::output_preview(output, share);
+            shortened |= cut;
+
+            if let Some(label) = label {
+                text.push_str(&format!("<b>{}</b>\n", label));
+            }
+            if config.exec_ansi_mode == AnsiMode::Html {
+                text.push_str(&ansi::html(&preview));
+                text.push_str("\n\n");
+            } else {
+                text.push_str(&formatting::html_code_block(&ansi::plain_text(&preview)));
+                text.push('\n');
+            }
+        }
+
+        text.push_str(&formatting::escape_html(status_line));
+        (text, shortened)
+    }
+
+    /// Splits off a leading `--timeout <seconds>`. Only admins may raise the timeout
+    /// past the configured one, other overrides are capped at it.
+    fn parse_exec_options(
+        command: &str,
+        default_timeout: u64,
+        may_exceed_default: bool,
+    ) -> Result<(u64, String), String> {
+        let mut timeout = default_timeout;
+        let mut command = command.trim();
+
+        if let Some(options) = command.strip_prefix("--timeout") {
+            let options = options.trim_start();
+            let (value, rest) = options
+                .split_once(char::is_whitespace)
+                .unwrap_or((options, ""));
+
+            timeout = match value.parse() {
+                // Zero would disable the limit altogether
+                Ok(0) | Err(_) => return Err(format!("Invalid timeout: {}", value)),
+                Ok(timeout) if !may_exceed_default && default_timeout != 0 => timeout.min(default_timeout),
+                Ok(timeout) => timeout,
+            };
+            command = rest.trim_start();
+        }
+
+        if command.is_empty() {
+            return Err("Usage: /exec [--timeout <seconds>] <command>".to_string());
+        }
+
+        Ok((timeout, command.to_string()))
+    }
+
+    fn stop_keyboard(job_id: u64) -> InlineKeyboardMarkup {
+        InlineKeyboardMarkup::new(vec![vec![InlineKeyboardButton::callback(
+            "⛔ Stop",
+            format!("job:stop:{}", job_id),
+        )]])
+    }
+
+    /// Spawns a command in the user's session. Only holds the session lock while
+    /// spawning, so other commands aren't blocked while it runs.
+    async fn spawn_in_session(
+        session_manager: &Arc<Mutex<SessionManager>>,
+        user_id: i64,
+        command: &str,
+        cwd: Option<&Path>,
+        config: &Config,
+    ) -> Result<(RunningCommand, PathBuf), BotError> {
+        let mut session_manager = session_manager.lock().await;
+        let session = session_manager.get_session(user_id)?;
+        session.record_command(command);
+
+        let cwd = cwd
+            .unwrap_or(session.file_manager.get_current_directory())
+            .to_path_buf();
+
+        let running = RunningCommand::spawn(
+            command,
+            &cwd,
+            &session.environment,
+            config,
+        )?;
+        Ok((running, cwd))
+    }
+
+    fn exit_status_line(result: &ExecResult) -> String {
+        let elapsed = result.duration.as_secs_f64();
+        let mut line = match (result.exit_code, result.signal) {
+            (Some(0), _) => format!("✅ Exit code 0 in {:.1}s", elapsed),
+            (Some(code), _) => format!("❌ Exit code {} in {:.1}s", code, elapsed),
+            (None, Some(signal)) => format!(
+                "❌ Killed by {} (signal {}) in {:.1}s",
+                Self::signal_name(signal),
+                signal,
+                elapsed
+            ),
+            (None, None) => format!("❌ Terminated in {:.1}s", elapsed),
+        };
+
+        if result.truncated {
+            line.push_str("\n✂️ Output exceeded the capture limit and was cut off");
+        }
+        if result.abandoned {
+            line.push_str("\n✂️ A process left running kept the output open, the rest was not captured");
+        }
+        line
+    }
+
+    fn signal_name(signal: i32) -> String {
+        match signal {
+            libc::SIGHUP => "SIGHUP".to_string(),
+            libc::SIGINT => "SIGINT".to_string(),
+            libc::SIGQUIT => "SIGQUIT".to_string(),
+            libc::SIGABRT => "SIGABRT".to_string(),
+            libc::SIGKILL => "SIGKILL".to_string(),
+            libc::SIGSEGV => "SIGSEGV".to_string(),
+            libc::SIGPIPE => "SIGPIPE".to_string(),
+            libc::SIGALRM => "SIGALRM".to_string(),
+            libc::SIGTERM => "SIGTERM".to_string(),
+            signal => format!("signal {}", signal),
+        }
+    }
+
+    /// Checks the command against the exec policy, logging and explaining rejections.
+    async fn check_policy(
+        bot: &Bot,
+        msg: &Message,
+        command: &str,
+        role: Role,
+        policy: &Policy,
+        log_manager: &LogManager,
+        audit: &mut AuditEntry,
+    ) -> Result<bool, BotError> {
+        let user_id = msg.chat.id.0;
+        let decision = policy.check(command, role, user_id);
+        audit.policy = Some(decision.to_string());
+
+        if let PolicyDecision::Allowed = decision {
+            return Ok(true);
+        }
+
+        log_manager.audit(audit)?;
+
+        log_manager.log(
+            log::Level::Warn,
+            &format!(
+                "Policy rejected command of user {} ({}): {} - {}",
+                user_id, role, command, decision
+            ),
+        )?;
+
+        bot.send_message(msg.chat.id, format!("⛔ Command rejected: {}", decision))
+            .await
+            .map_err(|e| BotError::TelegramError(e.to_string()))?;
+
+        Ok(false)
+    }
+
+    /// Shows the exact command and directory with Run/Cancel buttons instead of running it.
+    async fn request_confirmation(
+        bot: &Bot,
+        msg: &Message,
+        mut pending: PendingCommand,
+        session_manager: &Mutex<SessionManager>,
+        config: &Config,
+    ) -> Result<(), BotError> {
+        let window = Duration::from_secs(config.confirmation_timeout_secs);
+        let (text, pending_id) = {
+            let mut session_manager = session_manager.lock().await;
+            let session = session_manager.get_session(msg.chat.id.0)?;
+            pending.cwd = session.file_manager.get_current_directory().to_path_buf();
+
+            let text = format!(
+                "⚠️ This command looks dangerous:\n{}\n{}",
+                formatting::markdown_v2_code_block(&formatting::truncate(
+                    &pending.command,
+                    MAX_OUTPUT_CHARS
+                )),
+                formatting::escape_markdown_v2(&format!(
+                    "in {}{}\n\nRun it? The buttons expire in {}s.",
+                    pending.cwd.display(),
+                    if pending.background { " (in the background)" } else { "" },
+                    window.as_secs()
+                ))
+            );
+            (text, session.add_pending_command(pending, window))
+        };
+
+        let keyboard = InlineKeyboardMarkup::new(vec![vec![
+            InlineKeyboardButton::callback("▶️ Run", format!("confirm:run:{}", pending_id)),
+            InlineKeyboardButton::callback("✖️ Cancel", format!("confirm:cancel:{}", pending_id)),
+        ]]);
+
+        bot.send_message(msg.chat.id, text)
+            .parse_mode(ParseMode::MarkdownV2)
+            .reply_markup(keyboard)
+            .await
+            .map_err(|e| BotError::TelegramError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Handles the Run/Cancel buttons. Pending commands live in the issuer's session,
+    /// so nobody else can press them.
+    #[allow(clippy::too_many_arguments)]
+    async fn confirm_command(
+        bot: &Bot,
+        query: &CallbackQuery,
+        session_manager: &Arc<Mutex<SessionManager>>,
+        job_manager: &Arc<Mutex<JobManager>>,
+        log_manager: &Arc<LogManager>,
+        config: &Arc<Config>,
+        user_id: i64,
+        pending_id: &str,
+        run: bool,
+    ) -> Result<String, BotError> {
+        let window = Duration::from_secs(config.confirmation_timeout_secs);
+        let pending = match pending_id.parse::<u64>() {
+            Ok(pending_id) => session_manager
+                .lock()
+                .await
+                .get_session(user_id)?
+                .take_pending_command(pending_id, window),
+            Err(_) => None,
+        };
+
+        let Some(pending) = pending else {
+            let response = "⌛ This confirmation has expired".to_string();
+            if let Some(message) = &query.message {
+                bot.edit_message_text(message.chat.id, message.id, response.clone())
+                    .await
+                    .map_err(|e| BotError::TelegramError(e.to_string()))?;
+            }
+            return Ok(response);
+        };
+
+        let chat_id = query
+            .message
+            .as_ref()
+            .map(|message| message.chat.id)
+            .unwrap_or(ChatId(user_id));
+
+        let mut audit = Self::callback_audit_entry(
+            query,
+            if pending.background { "bg" } else { "exec" },
+            &pending.command,
+        );
+
+        let response = if run {
+            audit.policy = Some("confirmed".to_string());
+            format!("▶️ Confirmed: {}", pending.command)
+        } else {
+            audit.policy = Some("cancelled".to_string());
+            audit.cwd = Some(pending.cwd.clone());
+            log_manager.audit(&audit)?;
+            format!("✖️ Cancelled: {}", pending.command)
+        };
+
+        if let Some(message) = &query.message {
+            bot.edit_message_text(message.chat.id, message.id, response.clone())
+                .await
+                .map_err(|e| BotError::TelegramError(e.to_string()))?;
+        }
+
+        if !run {
+            return Ok(response);
+        }
+
+        log_manager.log(
+            log::Level::Info,
+            &format!("User {} confirmed command: {}", user_id, pending.command),
+        )?;
+
+        if pending.background {
+            Self::start_bg(
+                bot.clone(),
+                chat_id,
+                audit,
+                Some(pending.cwd),
+                session_manager.clone(),
+                job_manager.clone(),
+                log_manager.clone(),
+                config.clone(),
+            )
+            .await?;
+        } else {
+            Self::start_exec(
+                bot.clone(),
+                chat_id,
+                audit,
+                Some(pending.cwd),
+                pending.timeout,
+                session_manager.clone(),
+                job_manager.clone(),
+                log_manager.clone(),
+                config.clone(),
+            )
+            .await?;
+        }
+
+        Ok(response)
+    }
+
+    /// Holds back sensitive commands until the user confirmed them with `/totp <code>`.
+    async fn check_totp(
+        bot: &Bot,
+        msg: &Message,
+        command: &str,
+        auth_manager: &Mutex<AuthManager>,
+    ) -> Result<bool, BotError> {
+        let user_id = msg.chat.id.0;
+        let response = {
+            let auth_manager = auth_manager.lock().await;
+
+            if !auth_manager.requires_fresh_totp(user_id, command) {
+                return Ok(true);
+            }
+
+            if auth_manager.has_totp(user_id) {
+                "🔐 This command is sensitive. Confirm it with /totp <code> and send it again."
+            } else {
+                "🔐 This command is sensitive and needs TOTP. Ask an admin to enroll you."
+            }
+        };
+
+        bot.send_message(msg.chat.id, response)
+            .await
+            .map_err(|e| BotError::TelegramError(e.to_string()))?;
+
+        Ok(false)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn handle_exec(
+        bot: teloxide::Bot,
+        msg: Message,
+        command: String,
+        role: Role,
+        auth_manager: Arc<Mutex<AuthManager>>,
+        session_manager: Arc<Mutex<SessionManager>>,
+        job_manager: Arc<Mutex<JobManager>>,
+        log_manager: Arc<LogManager>,
+        policy: Arc<Policy>,
+        config: Arc<Config>,
+    ) -> Result<(), BotError> {
+        let (timeout, command) = match Self::parse_exec_options(&command, config.exec_timeout_secs, role == Role::Admin) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                bot.send_message(msg.chat.id, format!("❌ {}", e))
+                    .await
+                    .map_err(|e| BotError::TelegramError(e.to_string()))?;
+                return Ok(());
+            }
+        };
+
+        let mut audit = Self::audit_entry(&msg, "exec", &command);
+
+        if !Self::check_policy(&bot, &msg, &command, role, &policy, &log_manager, &mut audit)
+            .await?
+        {
+            return Ok(());
+        }
+
+        if !Self::check_totp(&bot, &msg, &command, &auth_manager).await? {
+            audit.policy = Some("awaiting TOTP confirmation".to_string());
+            return log_manager.audit(&audit);
+        }
+
+        if policy.needs_confirmation(&command) {
+            audit.policy = Some("awaiting confirmation".to_string());
+            log_manager.audit(&audit)?;
+
+            let pending = PendingCommand::new(command, timeout, false);
+            return Self::request_confirmation(&bot, &msg, pending, &session_manager, &config).await;
+        }
+
+        Self::start_exec(
+            bot,
+            msg.chat.id,
+            audit,
+            None,
+            timeout,
+            session_manager,
+            job_manager,
+            log_manager,
+            config,
+        )
+        .await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn start_exec(
+        bot: Bot,
+        chat_id: ChatId,
+        mut audit: AuditEntry,
+        cwd: Option<PathBuf>,
+        timeout: u64,
+        session_manager: Arc<Mutex<SessionManager>>,
+        job_manager: Arc<Mutex<JobManager>>,
+        log_manager: Arc<LogManager>,
+        config: Arc<Config>,
+    ) -> Result<(), BotError> {
+        let user_id = audit.user_id;
+        let command = audit.arguments.clone();
+
+        let spawned = Self::spawn_in_session(
+            &session_manager,
+            user_id,
+            &command,
+            cwd.as_deref(),
+            &config,
+        )
+        .await;
+        let running = match spawned {
+            Ok((running, cwd)) => {
+                audit.cwd = Some(cwd);
+                running
+            }
+            Err(e) => {
+                log_manager.audit(&audit)?;
+                bot.send_message(chat_id, format!("❌ Failed to execute command: {}", e))
+                    .await
+                    .map_err(|e| BotError::TelegramError(e.to_string()))?;
+                return Ok(());
+            }
+        };
+
+        let job_id = job_manager
+            .lock()
+            .await
+            .register(user_id, &command, running.process_group(), false);
+
+        // Updates from one chat are handled sequentially, so the command is monitored
+        // in the background to keep /kill and the stop button responsive
+        tokio::spawn(async move {
+            let result = Self::monitor_exec(
+                &bot,
+                chat_id,
+                running,
+                job_id,
+                timeout,
+                &log_manager,
+                &config,
+                &mut audit,
+            )
+            .await;
+
+            job_manager.lock().await.remove(job_id);
+
+            if let Err(e) = log_manager.audit(&audit) {
+                let _ = log_manager.log(
+                    log::Level::Error,
+                    &format!("Failed to audit `{}`: {}", command, e),
+                );
+            }
+
+            if let Err(e) = result {
+                let _ = log_manager.log(
+                    log::Level::Error,
+                    &format!("Failed to report result of `{}`: {}", command, e),
+                );
+            }
+        });
+
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn monitor_exec(
+        bot: &Bot,
+        chat_id: ChatId,
+        mut running: RunningCommand,
+        job_id: u64,
+        timeout: u64,
+        log_manager: &LogManager,
+        config: &Config,
+        audit: &mut AuditEntry,
+    ) -> Result<(), BotError> {
+        let command = audit.arguments.clone();
+        let (mut last_text, _) =
+            Self::render_exec_message(&command, &running.progress(), "⏳ Running...", config);
+        let message = match bot
+            .send_message(chat_id, last_text.clone())
+            .parse_mode(ParseMode::Html)
+            .reply_markup(Self::stop_keyboard(job_id))
+            .await
+        {
+            Ok(message) => message,
+            Err(e) => {
+                // The job is removed once this returns, nothing could stop the command then
+                terminate_process_group(running.process_group()).await;
+                let _ = running.wait().await;
+                return Err(BotError::TelegramError(e.to_string()));
+            }
+        };
+
+        let mut interval = tokio::time::interval(Duration::from_secs(
+            config.exec_update_interval_secs.max(1),
+        ));
+        interval.tick().await;
+
+        // A timeout of zero disables the limit
+        let deadline = async {
+            match timeout {
+                0 => std::future::pending().await,
+                seconds => tokio::time::sleep(Duration::from_secs(seconds)).await,
+            }
+        };
+        tokio::pin!(deadline);
+        let mut timed_out = false;
+
+        let status = loop {
+            tokio::select! {
+                status = running.wait() => break status?,
+                _ = &mut deadline, if !timed_out => {
+                    timed_out = true;
+                    tokio::spawn(terminate_process_group(running.process_group()));
+                }
+                _ = interval.tick() => {
+                    let status_line = format!("⏳ Running for {}s...", running.elapsed().as_secs());
+                    let (text, _) =
+                        Self::render_exec_message(&command, &running.progress(), &status_line, config);
+
+                    if text != last_text {
+                        // A failed progress update shouldn't abort the command
+                        let _ = bot
+                            .edit_message_text(chat_id, message.id, text.clone())
+                            .parse_mode(ParseMode::Html)
+                            .reply_markup(Self::stop_keyboard(job_id))
+                            .await;
+                        last_text = text;
+                    }
+                }
+            }
+        };
+
+        let result = running.result(Some(status));
+        audit.set_result(&result);
+
+        let status_line = if timed_out {
+            format!("⏱ Timed out after {}s\n{}", timeout, Self::exit_status_line(&result))
+        } else {
+            Self::exit_status_line(&result)
+        };
+
+        let (text, attach) = Self::render_exec_message(&command, &result, &status_line, config);
+
+        bot.edit_message_text(chat_id, message.id, text)
+            .parse_mode(ParseMode::Html)
+            .await
+            .map_err(|e| BotError::TelegramError(e.to_string()))?;
+
+        Self::send_job_output(bot, chat_id, job_id, &running, &result, attach, log_manager, config)
+            .await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn handle_bg(
+        bot: teloxide::Bot,
+        msg: Message,
+        command: String,
+        role: Role,
+        auth_manager: Arc<Mutex<AuthManager>>,
+        session_manager: Arc<Mutex<SessionManager>>,
+        job_manager: Arc<Mutex<JobManager>>,
+        log_manager: Arc<LogManager>,
+        policy: Arc<Policy>,
+        config: Arc<Config>,
+    ) -> Result<(), BotError> {
+        let command = command.trim().to_string();
+
+        if command.is_empty() {
+            bot.send_message(msg.chat.id, "❌ Usage: /bg <command>")
+                .await
+                .map_err(|e| BotError::TelegramError(e.to_string()))?;
+            return Ok(());
+        }
+
+        let mut audit = Self::audit_entry(&msg, "bg", &command);
+
+        if !Self::check_policy(&bot, &msg, &command, role, &policy, &log_manager, &mut audit)
+            .await?
+        {
+            return Ok(());
+        }
+
+        if !Self::check_totp(&bot, &msg, &command, &auth_manager).await? {
+            audit.policy = Some("awaiting TOTP confirmation".to_string());
+            return log_manager.audit(&audit);
+        }
+
+        if policy.needs_confirmation(&command) {
+            audit.policy = Some("awaiting confirmation".to_string());
+            log_manager.audit(&audit)?;
+
+            let pending = PendingCommand::new(command, 0, true);
+            return Self::request_confirmation(&bot, &msg, pending, &session_manager, &config).await;
+        }
+
+        Self::start_bg(
+            bot,
+            msg.chat.id,
+            audit,
+            None,
+            session_manager,
+            job_manager,
+            log_manager,
+            config,
+        )
+        .await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn start_bg(
+        bot: Bot,
+        chat_id: ChatId,
+        mut audit: AuditEntry,
+        cwd: Option<PathBuf>,
+        session_manager: Arc<Mutex<SessionManager>>,
+        job_manager: Arc<Mutex<JobManager>>,
+        log_manager: Arc<LogManager>,
+        config: Arc<Config>,
+    ) -> Result<(), BotError> {
+        let user_id = audit.user_id;
+        let command = audit.arguments.clone();
+
+        let spawned = Self::spawn_in_session(
+            &session_manager,
+            user_id,
+            &command,
+            cwd.as_deref(),
+            &config,
+        )
+        .await;
+        let running = match spawned {
+            Ok((running, cwd)) => {
+                audit.cwd = Some(cwd);
+                running
+            }
+            Err(e) => {
+                log_manager.audit(&audit)?;
+                bot.send_message(chat_id, format!("❌ Failed to execute command: {}", e))
+                    .await
+                    .map_err(|e| BotError::TelegramError(e.to_string()))?;
+                return Ok(());
+            }
+        };
+
+        let job_id = job_manager
+            .lock()
+            .await
+            .register(user_id, &command, running.process_group(), true);
+
+        // The job has to be waited for either way, or it would stay running in /jobs
+        if let Err(e) = bot
+            .send_message(
+                chat_id,
+                format!("🚀 Started background job #{}. Use /jobs to check on it.", job_id),
+            )
+            .await
+        {
+            log_manager.log(
+                log::Level::Warn,
+                &format!("Failed to announce background job #{}: {}", job_id, e),
+            )?;
+        }
+
+        tokio::spawn(async move {
+            let result = Self::wait_background_job(
+                &bot,
+                chat_id,
+                running,
+                job_id,
+                &job_manager,
+                &log_manager,
+                &mut audit,
+                &config,
+            )
+            .await;
+
+            if let Err(e) = log_manager.audit(&audit) {
+                let _ = log_manager.log(
+                    log::Level::Error,
+                    &format!("Failed to audit background job #{}: {}", job_id, e),
+                );
+            }
+
+            if let Err(e) = result {
+                let _ = log_manager.log(
+                    log::Level::Error,
+                    &format!("Failed to report background job #{}: {}", job_id, e),
+                );
+            }
+        });
+
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn wait_background_job(
+        bot: &Bot,
+        chat_id: ChatId,
+        mut running: RunningCommand,
+        job_id: u64,
+        job_manager: &Arc<Mutex<JobManager>>,
+        log_manager: &LogManager,
+        audit: &mut AuditEntry,
+        config: &Config,
+    ) -> Result<(), BotError> {
+        let status = running.wait().await;
+
+        let result = running.result(status.as_ref().ok().copied());
+        let status_line = match status {
+            Ok(_) => {
+                audit.set_result(&result);
+                Self::exit_status_line(&result)
+            }
+            Err(e) => format!("❌ {}", e),
+        };
+        job_manager.lock().await.finish(job_id, status_line.clone());
+
+        let (text, attach) =
+            Self::render_exec_message(&audit.arguments, &result, &status_line, config);
+        let notification = format!("🔔 Background job #{} finished\n\n{}", job_id, text);
+
+        bot.send_message(chat_id, notification)
+            .parse_mode(ParseMode::Html)
+            .await
+            .map_err(|e| BotError::TelegramError(e.to_string()))?;
+
+        Self::send_job_output(bot, chat_id, job_id, &running, &result, attach, log_manager, config)
+            .await
+    }
+
+    /// Sends what follows the final message of a job: the output as an image in
+    /// image mode, and the full output files when the message had to shorten it.
+    #[allow(clippy::too_many_arguments)]
+    async fn send_job_output(
+        bot: &Bot,
+        chat_id: ChatId,
+        job_id: u64,
+        running: &RunningCommand,
+        result: &ExecResult,
+        attach: bool,
+        log_manager: &LogManager,
+        config: &Config,
+    ) -> Result<(), BotError> {
+        let name = format!("job-{}", job_id);
+
+        if config.exec_ansi_mode == AnsiMode::Image {
+            // E.g. a missing font; the attachments below still carry the output
+            if let Err(e) = Self::send_output_image(bot, chat_id, &name, result, config).await {
+                log_manager.log(
+                    log::Level::Warn,
+                    &format!("Failed to send output image of job #{}: {}", job_id, e),
+                )?;
+            }
+        }
+
+        if attach {
+            Self::send_output_files(bot, chat_id, &name, running, config).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Shortens output longer than `max_len` to its head and tail, which usually
+    /// tell what happened, and reports whether it had to.
+    fn output_preview(output: &[Span], max_len: usize) -> (Vec<Span>, bool) {
+        if ansi::text_len(output) <= max_len {
+            return (output.to_vec(), false);
+        }
+
+        let mut preview = ansi::head(output, max_len / 3);
+        let tail = ansi::tail(output, max_len - ansi::text_len(&preview));
+
+        let text = ansi::plain_text(output);
+        let omitted = &text[ansi::plain_text(&preview).len()..text.len() - ansi::plain_text(&tail).len()];
+
+        preview.push(Span {
+            style: Style::default(),
+            text: format!(
+                "\n[… {} characters, {} lines omitted, full output attached …]\n",
+                omitted.chars().count(),
+                omitted.matches('\n').count()
+            ),
+        });
+        preview.extend(tail);
+        (preview, true)
+    }
+
+    /// Sends the output as a picture of a terminal, keeping its colors.
+    async fn send_output_image(
+        bot: &Bot,
+        chat_id: ChatId,
+        name: &str,
+        result: &ExecResult,
+        config: &Config,
+    ) -> Result<(), BotError> {
+        let output = ansi::parse(&result.combined);
+        if output.is_empty() {
+            return Ok(());
+        }
+
+        let (preview, _) = Self::output_preview(&output, MAX_OUTPUT_CHARS);
+        let image = ansi::render_png(&preview, &config.ansi_font_path)?;
+
+        bot.send_photo(chat_id, InputFile::memory(image).file_name(format!("{}.png", name)))
+            .await
+            .map_err(|e| BotError::TelegramError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Sends stdout and stderr as separate text files, gzipped if configured.
+    async fn send_output_files(
+        bot: &Bot,
+        chat_id: ChatId,
+        name: &str,
+        running: &RunningCommand,
+        config: &Config,
+    ) -> Result<(), BotError> {
+        for (stream, output) in [("stdout", running.stdout()), ("stderr", running.stderr())] {
+            let data = output.bytes();
+            if data.is_empty() {
+                continue;
+            }
+
+            let file = if config.compress_output_attachments {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder
+                    .write_all(&data)
+                    .and_then(|_| encoder.finish())
+                    .map(|compressed| {
+                        InputFile::memory(compressed).file_name(format!("{}-{}.txt.gz", name, stream))
+                    })
+                    .map_err(|e| BotError::ExecError(format!("Failed to compress output: {}", e)))?
+            } else {
+                InputFile::memory(data).file_name(format!("{}-{}.txt", name, stream))
+            };
+
+            bot.send_document(chat_id, file)
+                .await
+                .map_err(|e| BotError::TelegramError(e.to_string()))?;
+        }
+
+        Ok(())
+    }
+
+    async fn stop_job(job_manager: &Arc<Mutex<JobManager>>, user_id: i64, job_id: &str) -> String {
+        let Ok(job_id) = job_id.trim().parse::<u64>() else {
+            return "❌ Usage: /kill <job id>".to_string();
+        };
+
+        match job_manager.lock().await.get_user_job(user_id, job_id) {
+            Some(job) => {
+                tokio::spawn(terminate_process_group(job.process_group));
+                format!("⛔ Stopping job {}", job_id)
+            }
+            None => format!("❌ No running job {}", job_id),
+        }
+    }
+
+    /// Stops every running job of the user, returning how many there were.
+    async fn stop_user_jobs(job_manager: &Arc<Mutex<JobManager>>, user_id: i64) -> usize {
+        let job_manager = job_manager.lock().await;
+        let running: Vec<u32> = job_manager
+            .get_user_jobs(user_id)
+            .into_iter()
+            .filter(|job| matches!(job.state, JobState::Running))
+            .map(|job| job.process_group)
+            .collect();
+
+        for process_group in &running {
+            tokio::spawn(terminate_process_group(*process_group));
+        }
+        running.len()
+    }
+
+    fn stopped_jobs_note(stopped: usize) -> String {
+        match stopped {
+            0 => String::new(),
+            1 => ", stopping their running job".to_string(),
+            stopped => format!(", stopping their {} running jobs", stopped),
+        }
+    }
+
+    async fn handle_jobs(
+        bot: teloxide::Bot,
+        msg: Message,
+        job_manager: Arc<Mutex<JobManager>>,
+    ) -> Result<(), BotError> {
+        let user_id = msg.chat.id.0;
+
+        // Not held while sending, the Stop button, /kill and finishing jobs need it
+        let response = {
+            let job_manager = job_manager.lock().await;
+            let jobs = job_manager.get_user_jobs(user_id);
+
+            if jobs.is_empty() {
+                "🔧 No jobs".to_string()
+            } else {
+                let mut response = String::from("🔧 Jobs:\n");
+                for job in jobs {
+                    let kind = if job.background { "bg" } else { "fg" };
+                    let state = match &job.state {
+                        JobState::Running => {
+                            format!("⏳ running for {}s", job.started_at.elapsed().as_secs())
+                        }
+                        JobState::Finished(summary) => summary.clone(),
+                    };
+
+                    response.push_str(&format!(
+                        "#{} [{}] {}\n    {}\n",
+                        job.id, kind, job.command, state
+                    ));
+                }
+                response
+            }
+        };
+
+        Self::send_text(&bot, msg.chat.id, &response).await
+    }
+
+    async fn handle_kill(
+        bot: teloxide::Bot,
+        msg: Message,
+        job_id: String,
+        job_manager: Arc<Mutex<JobManager>>,
+    ) -> Result<(), BotError> {
+        let user_id = msg.chat.id.0;
+        let response = Self::stop_job(&job_manager, user_id, &job_id).await;
+
+        bot.send_message(msg.chat.id, response)
+            .await
+            .map_err(|e| BotError::TelegramError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    async fn handle_pwd(
+        bot: teloxide::Bot,
+        msg: Message,
+        session_manager: Arc<Mutex<SessionManager>>,
+    ) -> Result<(), BotError> {
+        let user_id = msg.chat.id.0;
+        let current_dir = session_manager
+            .lock()
+            .await
+            .get_session(user_id)?
+            .file_manager
+            .get_current_directory()
+            .to_path_buf();
+
+        bot.send_message(
+            msg.chat.id,
+            format!("📁 Current directory: {}", current_dir.display()),
+        )
+        .await
+        .map_err(|e| BotError::TelegramError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    fn is_valid_variable_name(name: &str) -> bool {
+        let mut chars = name.chars();
+        chars
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+    }
+
+    /// Accepts both `NAME value` and `NAME=value`.
+    fn parse_setenv(args: &str) -> Result<(String, String), String> {
+        let args = args.trim();
+        let (name, value) = match (args.find('='), args.find(char::is_whitespace)) {
+            (Some(equals), Some(space)) if equals < space => args.split_at(equals),
+            (Some(equals), None) => args.split_at(equals),
+            (_, Some(space)) => args.split_at(space),
+            (None, None) => (args, ""),
+        };
+        let value = value.strip_prefix('=').unwrap_or(value.trim_start());
+
+        if !Self::is_valid_variable_name(name) {
+            return Err("Usage: /setenv <name> <value>".to_string());
+        }
+
+        Ok((name.to_string(), value.to_string()))
+    }
+
+    async fn handle_env(
+        bot: teloxide::Bot,
+        msg: Message,
+        name: String,
+        session_manager: Arc<Mutex<SessionManager>>,
+        config: Arc<Config>,
+    ) -> Result<(), BotError> {
+        let user_id = msg.chat.id.0;
+        let variables = {
+            let mut session_manager = session_manager.lock().await;
+            let session = session_manager.get_session(user_id)?;
+            executor::effective_environment(&config, &session.environment)
+        };
+
+        let name = name.trim();
+        if !name.is_empty() {
+            let response = match variables.get(name) {
+                Some(value) => format!(
+                    "{}={}",
+                    formatting::escape_html(name),
+                    formatting::html_inline_code(value)
+                ),
+                None => format!("❌ {} is not set", formatting::escape_html(name)),
+            };
+
+            bot.send_message(msg.chat.id, response)
+                .parse_mode(ParseMode::Html)
+                .await
+                .map_err(|e| BotError::TelegramError(e.to_string()))?;
+            return Ok(());
+        }
+
+        let mut listing = String::new();
+        for (name, value) in &variables {
+            listing.push_str(&format!("{}={}\n", name, value));
+        }
+
+        if listing.is_empty() {
+            listing.push_str("(empty)");
+        }
+        if config.exec_login_shell {
+            listing.push_str("\n# The login shell's profile files may add more");
+        }
+
+        for block in formatting::split_html_code_blocks(&listing, MAX_OUTPUT_CHARS) {
+            bot.send_message(msg.chat.id, block)
+                .parse_mode(ParseMode::Html)
+                .await
+                .map_err(|e| BotError::TelegramError(e.to_string()))?;
+        }
+
+        Ok(())
+    }
+
+    async fn handle_setenv(
+        bot: teloxide::Bot,
+        msg: Message,
+        args: String,
+        session_manager: Arc<Mutex<SessionManager>>,
+    ) -> Result<(), BotError> {
+        let user_id = msg.chat.id.0;
+
+        let response = match Self::parse_setenv(&args) {
+            Ok((name, value)) => {
+                let mut session_manager = session_manager.lock().await;
+                let session = session_manager.get_session(user_id)?;
+                session.environment.insert(name.clone(), Some(value));
+                format!("✅ Set {} for your commands", name)
+            }
+            Err(usage) => usage,
+        };
+
+        bot.send_message(msg.chat.id, response)
+            .await
+            .map_err(|e| BotError::TelegramError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    async fn handle_unsetenv(
+        bot: teloxide::Bot,
+        msg: Message,
+        name: String,
+        session_manager: Arc<Mutex<SessionManager>>,
+    ) -> Result<(), BotError> {
+        let user_id = msg.chat.id.0;
+        let name = name.trim();
+
+        let response = if Self::is_valid_variable_name(name) {
+            let mut session_manager = session_manager.lock().await;
+            let session = session_manager.get_session(user_id)?;
+            // Also hides the variable if it comes from the base environment
+            session.environment.insert(name.to_string(), None);
+            format!("✅ Removed {} for your commands", name)
+        } else {
+            "Usage: /unsetenv <name>".to_string()
+        };
+
+        bot.send_message(msg.chat.id, response)
+            .await
+            .map_err(|e| BotError::TelegramError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    fn shell_keyboard() -> InlineKeyboardMarkup {
+        InlineKeyboardMarkup::new(vec![vec![
+            InlineKeyboardButton::callback("Ctrl-C", "shell:ctrl_c"),
+            InlineKeyboardButton::callback("Ctrl-D", "shell:ctrl_d"),
+        ]])
+    }
+
+    async fn write_to_shell(
+        session_manager: &Arc<Mutex<SessionManager>>,
+        user_id: i64,
+        data: &[u8],
+    ) -> Result<&'static str, BotError> {
+        let mut session_manager = session_manager.lock().await;
+
+        match session_manager.get_session(user_id)?.active_shell() {
+            Some(shell) => {
+                shell.write(data)?;
+                Ok("✅ Sent")
+            }
+            None => Ok("❌ No active shell"),
+        }
+    }
+
+    /// Relays terminal output to the chat, batching bursts into a single message.
+    async fn forward_shell_output(
+        bot: Bot,
+        chat_id: ChatId,
+        mut receiver: UnboundedReceiver<Vec<u8>>,
+    ) {
+        // Keeps escape sequences and characters cut in half at the end of a batch
+        let mut terminal = Terminal::new();
+
+        while let Some(chunk) = receiver.recv().await {
+            terminal.feed(&chunk);
+
+            let batch_deadline = tokio::time::Instant::now() + SHELL_OUTPUT_BATCH_DELAY;
+            while let Ok(Some(chunk)) = tokio::time::timeout_at(batch_deadline, receiver.recv()).await
+            {
+                terminal.feed(&chunk);
+            }
+
+            let text = ansi::plain_text(&terminal.take());
+
+            for block in formatting::split_html_code_blocks(&text, MAX_OUTPUT_CHARS) {
+                let _ = bot
+                    .send_message(chat_id, block)
+                    .parse_mode(ParseMode::Html)
+                    .reply_markup(Self::shell_keyboard())
+                    .await;
+            }
+        }
+
+        let _ = bot.send_message(chat_id, "🐚 Shell exited").await;
+    }
+
+    async fn handle_shell(
+        bot: teloxide::Bot,
+        msg: Message,
+        auth_manager: Arc<Mutex<AuthManager>>,
+        session_manager: Arc<Mutex<SessionManager>>,
+        config: Arc<Config>,
+    ) -> Result<(), BotError> {
+        let user_id = msg.chat.id.0;
+
+        // A shell can run anything, so it needs the same confirmation as sensitive commands
+        let response = {
+            let auth_manager = auth_manager.lock().await;
+            if !auth_manager.shell_requires_fresh_totp(user_id) {
+                None
+            } else if auth_manager.has_totp(user_id) {
+                Some("🔐 Starting a shell is sensitive. Confirm it with /totp <code> and send /shell again.")
+            } else {
+                Some("🔐 Starting a shell is sensitive and needs TOTP. Ask an admin to enroll you.")
+            }
+        };
+        if let Some(response) = response {
+            bot.send_message(msg.chat.id, response)
+                .await
+                .map_err(|e| BotError::TelegramError(e.to_string()))?;
+            return Ok(());
+        }
+
+        let spawned = {
+            let mut session_manager = session_manager.lock().await;
+            let session = session_manager.get_session(user_id)?;
+
+            if session.active_shell().is_some() {
+                Err("🐚 Shell is already running. Use /exit to close it.".to_string())
+            } else {
+                match PtyShell::spawn(
+                    session.file_manager.get_current_directory(),
+                    &session.environment,
+                    &config,
+                ) {
+                    Ok((shell, output)) => {
+                        session.shell = Some(shell);
+                        Ok(output)
+                    }
+                    Err(e) => Err(format!("❌ {}", e)),
+                }
+            }
+        };
+
+        let output = match spawned {
+            Ok(output) => output,
+            Err(response) => {
+                bot.send_message(msg.chat.id, response)
+                    .await
+                    .map_err(|e| BotError::TelegramError(e.to_string()))?;
+                return Ok(());
+            }
+        };
+        tokio::spawn(Self::forward_shell_output(bot.clone(), msg.chat.id, output));
+
+        bot.send_message(
+            msg.chat.id,
+            "🐚 Shell started. Every message you send is typed into it, /exit closes it.",
+        )
+        .reply_markup(Self::shell_keyboard())
         .await
         .map_err(|e| BotError::TelegramError(e.to_string()))?;
 
         Ok(())
     }
+
+    async fn handle_exit_shell(
+        bot: teloxide::Bot,
+        msg: Message,
+        session_manager: Arc<Mutex<SessionManager>>,
+    ) -> Result<(), BotError> {
+        let user_id = msg.chat.id.0;
+
+        // Dropping the shell kills it; the output forwarder then reports the exit
+        let had_shell = {
+            let mut session_manager = session_manager.lock().await;
+            let session = session_manager.get_session(user_id)?;
+            let had_shell = session.active_shell().is_some();
+            session.shell = None;
+            had_shell
+        };
+
+        if !had_shell {
+            bot.send_message(msg.chat.id, "❌ No active shell")
+                .await
+                .map_err(|e| BotError::TelegramError(e.to_string()))?;
+        }
+
+        Ok(())
+    }
+
+    fn format_timestamp(secs: u64) -> String {
+        DateTime::from_timestamp(secs as i64, 0)
+            .map(|time| time.with_timezone(&Local).format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_else(|| "unknown".to_string())
+    }
+
+    fn parse_user_id(target: &str) -> Result<i64, String> {
+        target
+            .trim()
+            .parse()
+            .map_err(|_| format!("Invalid user id: {}", target.trim()))
+    }
+
+    async fn handle_users(
+        bot: teloxide::Bot,
+        msg: Message,
+        auth_manager: Arc<Mutex<AuthManager>>,
+    ) -> Result<(), BotError> {
+        // Not held while sending, every authorization check would wait for it
+        let (mut users, signed_out_roles, totp_roles) = {
+            let auth_manager = auth_manager.lock().await;
+            let users: Vec<_> = auth_manager.get_authorized_users().values().cloned().collect();
+            (users, auth_manager.get_signed_out_roles().clone(), auth_manager.get_totp_roles())
+        };
+        users.sort_by_key(|user| (std::cmp::Reverse(user.role), user.user_id));
+
+        let totp_marker = |user_id: i64| if totp_roles.contains_key(&user_id) { ", TOTP" } else { "" };
+
+        let mut response = String::from("👥 Authorized users:\n");
+        for user in &users {
+            let username = user
+                .username
+                .as_ref()
+                .map(|name| format!("@{}", name))
+                .unwrap_or_else(|| "-".to_string());
+            let authorized_at = user
+                .authorized_at
+                .parse()
+                .map(Self::format_timestamp)
+                .unwrap_or_else(|_| user.authorized_at.clone());
+            let last_seen = user
+                .last_seen
+                .map(Self::format_timestamp)
+                .unwrap_or_else(|| "never".to_string());
+
+            response.push_str(&format!(
+                "\n{} {} [{}{}]\n    authorized {}, last seen {}\n",
+                user.user_id,
+                username,
+                user.role,
+                totp_marker(user.user_id),
+                authorized_at,
+                last_seen
+            ));
+        }
+
+        // Accounts that can sign in again without a new invite: with an access code
+        // after signing out, or with `/auth <totp>` once enrolled
+        let mut dormant: Vec<_> = signed_out_roles
+            .iter()
+            .map(|(user_id, role)| (*user_id, *role, "signed out"))
+            .chain(
+                totp_roles
+                    .iter()
+                    .filter(|(user_id, _)| {
+                        !signed_out_roles.contains_key(user_id)
+                            && users.iter().all(|user| user.user_id != **user_id)
+                    })
+                    .map(|(user_id, role)| (*user_id, *role, "TOTP enrollment, never signed in")),
+            )
+            .collect();
+        dormant.sort_by_key(|(user_id, role, _)| (std::cmp::Reverse(*role), *user_id));
+
+        if !dormant.is_empty() {
+            response.push_str("\n💤 Not signed in:\n");
+            for (user_id, role, state) in dormant {
+                response.push_str(&format!("\n{} [{}{}]\n    {}\n", user_id, role, totp_marker(user_id), state));
+            }
+        }
+
+        Self::send_text(&bot, msg.chat.id, &response).await
+    }
+
+    async fn handle_revoke(
+        bot: teloxide::Bot,
+        msg: Message,
+        target: String,
+        auth_manager: Arc<Mutex<AuthManager>>,
+        session_manager: Arc<Mutex<SessionManager>>,
+        job_manager: Arc<Mutex<JobManager>>,
+    ) -> Result<(), BotError> {
+        let response = match Self::parse_user_id(&target) {
+            Ok(target_id) => match auth_manager.lock().await.revoke(target_id) {
+                Ok(()) => {
+                    // Drops their working directory, environment and interactive shell
+                    session_manager.lock().await.remove_session(target_id);
+                    let stopped = Self::stop_user_jobs(&job_manager, target_id).await;
+                    format!("🚫 Revoked access of user {}{}", target_id, Self::stopped_jobs_note(stopped))
+                }
+                Err(e) => format!("❌ {}", e),
+            },
+            Err(e) => format!("❌ {}", e),
+        };
+
+        bot.send_message(msg.chat.id, response)
+            .await
+            .map_err(|e| BotError::TelegramError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    async fn handle_change_role(
+        bot: teloxide::Bot,
+        msg: Message,
+        target: String,
+        promote: bool,
+        auth_manager: Arc<Mutex<AuthManager>>,
+        session_manager: Arc<Mutex<SessionManager>>,
+        job_manager: Arc<Mutex<JobManager>>,
+    ) -> Result<(), BotError> {
+        let response = match Self::parse_user_id(&target) {
+            Ok(target_id) => {
+                let mut auth_manager = auth_manager.lock().await;
+
+                match auth_manager.get_role(target_id) {
+                    None => format!("❌ User {} is not authorized", target_id),
+                    Some(role) => {
+                        let new_role = if promote { role.promoted() } else { role.demoted() };
+
+                        match new_role {
+                            None => format!("ℹ️ User {} already is {}", target_id, role),
+                            Some(new_role) => match auth_manager.set_role(target_id, new_role) {
+                                Ok(()) => {
+                                    // An environment or shell set up with the old role must not outlive it
+                                    if !new_role.has_permission(Permission::Shell) {
+                                        session_manager.lock().await.remove_session(target_id);
+                                    }
+                                    // So do commands it allowed, the policy may deny them to the new role
+                                    let stopped = if promote {
+                                        0
+                                    } else {
+                                        Self::stop_user_jobs(&job_manager, target_id).await
+                                    };
+                                    format!(
+                                        "✅ User {} is now {}{}",
+                                        target_id,
+                                        new_role,
+                                        Self::stopped_jobs_note(stopped)
+                                    )
+                                }
+                                Err(e) => format!("❌ {}", e),
+                            },
+                        }
+                    }
+                }
+            }
+            Err(e) => format!("❌ {}", e),
+        };
+
+        bot.send_message(msg.chat.id, response)
+            .await
+            .map_err(|e| BotError::TelegramError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    async fn handle_invite(
+        bot: teloxide::Bot,
+        msg: Message,
+        role: String,
+        auth_manager: Arc<Mutex<AuthManager>>,
+        config: Arc<Config>,
+    ) -> Result<(), BotError> {
+        let role = if role.trim().is_empty() {
+            Ok(config.default_role)
+        } else {
+            role.parse::<Role>()
+        };
+
+        let response = match role {
+            Ok(role) => {
+                let code = auth_manager.lock().await.create_invite(role)?;
+                format!(
+                    "🎟 One-time invite for role {}. Forward this to the new user:\n\n/auth {}",
+                    role, code
+                )
+            }
+            Err(e) => format!("❌ {}", e),
+        };
+
+        bot.send_message(msg.chat.id, response)
+            .await
+            .map_err(|e| BotError::TelegramError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn handle_totp(
+        bot: teloxide::Bot,
+        msg: Message,
+        args: String,
+        role: Role,
+        auth_manager: Arc<Mutex<AuthManager>>,
+        log_manager: Arc<LogManager>,
+        config: Arc<Config>,
+    ) -> Result<(), BotError> {
+        let user_id = msg.chat.id.0;
+        let args: Vec<&str> = args.split_whitespace().collect();
+
+        let response = match args.as_slice() {
+            [] => {
+                if auth_manager.lock().await.has_totp(user_id) {
+                    "🔐 TOTP is enabled. Send /totp <code> before sensitive commands.".to_string()
+                } else {
+                    "🔓 TOTP is not enabled for you. Ask an admin to enroll you.".to_string()
+                }
+            }
+            ["enroll", ..] | ["disable", ..] if !role.has_permission(Permission::ManageUsers) => {
+                format!("⛔ Your role ({}) doesn't allow this command.", role)
+            }
+            ["enroll", target, rest @ ..] if rest.len() <= 1 => {
+                match Self::parse_user_id(target) {
+                    Ok(target_id) => {
+                        Self::enroll_totp(&bot, &msg, target_id, rest.first().copied(), &auth_manager, &config)
+                            .await?;
+                        log_manager.log(
+                            log::Level::Info,
+                            &format!("User {} enrolled user {} for TOTP", user_id, target_id),
+                        )?;
+                        return Ok(());
+                    }
+                    Err(e) => format!("❌ {}", e),
+                }
+            }
+            ["disable", target] => match Self::parse_user_id(target) {
+                Ok(target_id) => {
+                    if auth_manager.lock().await.disable_totp(target_id)? {
+                        log_manager.log(
+                            log::Level::Info,
+                            &format!("User {} disabled TOTP of user {}", user_id, target_id),
+                        )?;
+                        format!("🔓 TOTP disabled for user {}", target_id)
+                    } else {
+                        format!("ℹ️ User {} has no TOTP secret", target_id)
+                    }
+                }
+                Err(e) => format!("❌ {}", e),
+            },
+            [code] => match auth_manager.lock().await.verify_totp(user_id, code)? {
+                VerifyStatus::Authorized(_) => format!(
+                    "✅ Confirmed. Sensitive commands are allowed for {} minutes.",
+                    config.totp_freshness_secs / 60
+                ),
+                VerifyStatus::LockedOut(lockout) => {
+                    log_manager.log(
+                        log::Level::Warn,
+                        &format!("Failed TOTP attempt by user {}: locked out", user_id),
+                    )?;
+                    format!("⏳ Too many failed attempts, try again in {}s.", lockout.as_secs().max(1))
+                }
+                VerifyStatus::Invalid { .. } | VerifyStatus::Expired => {
+                    log_manager.log(
+                        log::Level::Warn,
+                        &format!("Failed TOTP attempt by user {}: invalid code", user_id),
+                    )?;
+                    "❌ Wrong TOTP code.".to_string()
+                }
+            },
+            _ => "❌ Usage: /totp <code>, /totp enroll <user id> [role] or /totp disable <user id>"
+                .to_string(),
+        };
+
+        bot.send_message(msg.chat.id, response)
+            .await
+            .map_err(|e| BotError::TelegramError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Creates the secret and sends it to the admin, who passes it on to the user.
+    async fn enroll_totp(
+        bot: &Bot,
+        msg: &Message,
+        target_id: i64,
+        role: Option<&str>,
+        auth_manager: &Mutex<AuthManager>,
+        config: &Config,
+    ) -> Result<(), BotError> {
+        let (secret, account, role) = {
+            let mut auth_manager = auth_manager.lock().await;
+
+            let role = match role {
+                Some(role) => role.parse::<Role>(),
+                None => Ok(auth_manager.get_role(target_id).unwrap_or(config.default_role)),
+            };
+            let role = match role {
+                Ok(role) => role,
+                Err(e) => {
+                    drop(auth_manager);
+                    bot.send_message(msg.chat.id, format!("❌ {}", e))
+                        .await
+                        .map_err(|e| BotError::TelegramError(e.to_string()))?;
+                    return Ok(());
+                }
+            };
+
+            let account = auth_manager
+                .get_authorized_users()
+                .get(&target_id)
+                .and_then(|user| user.username.clone())
+                .unwrap_or_else(|| target_id.to_string());
+
+            (auth_manager.enroll_totp(target_id, role)?, account, role)
+        };
+
+        let uri = totp::provisioning_uri(&secret, &config.totp_issuer, &account);
+        let qr_code = totp::qr_code_png(&uri)?;
+
+        bot.send_photo(msg.chat.id, InputFile::memory(qr_code).file_name("totp.png"))
+            .caption(format!(
+                "🔐 TOTP secret for user {} (role {}). Pass the QR code or this secret on \
+                privately: {}\n\nThey can then authorize with /auth <code>.",
+                target_id, role, secret
+            ))
+            .await
+            .map_err(|e| BotError::TelegramError(e.to_string()))?;
+
+        bot.send_message(msg.chat.id, uri)
+            .await
+            .map_err(|e| BotError::TelegramError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    async fn handle_history(
+        bot: teloxide::Bot,
+        msg: Message,
+        session_manager: Arc<Mutex<SessionManager>>,
+    ) -> Result<(), BotError> {
+        let user_id = msg.chat.id.0;
+        let response = {
+            let mut session_manager = session_manager.lock().await;
+            let history = session_manager.get_session(user_id)?.get_history();
+
+            if history.is_empty() {
+                "📜 History is empty".to_string()
+            } else {
+                let mut response = String::from("📜 Command history:\n");
+                for (index, command) in history.iter().enumerate() {
+                    response.push_str(&format!("{}. {}\n", index + 1, command));
+                }
+                response
+            }
+        };
+
+        Self::send_text(&bot, msg.chat.id, &response).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exec_options_set_the_timeout() {
+        assert_eq!(
+            BotManager::parse_exec_options("ls -l", 60, false),
+            Ok((60, "ls -l".to_string()))
+        );
+        assert_eq!(
+            BotManager::parse_exec_options("--timeout 5  make test", 60, false),
+            Ok((5, "make test".to_string()))
+        );
+    }
+
+    #[test]
+    fn exec_options_reject_disabling_the_timeout() {
+        for command in ["--timeout 0 ls", "--timeout -1 ls", "--timeout soon ls"] {
+            assert!(BotManager::parse_exec_options(command, 60, true).is_err(), "{}", command);
+        }
+        assert!(BotManager::parse_exec_options("--timeout 5", 60, true).is_err());
+    }
+
+    #[test]
+    fn only_admins_exceed_the_configured_timeout() {
+        assert_eq!(BotManager::parse_exec_options("--timeout 600 ls", 60, false).unwrap().0, 60);
+        assert_eq!(BotManager::parse_exec_options("--timeout 600 ls", 60, true).unwrap().0, 600);
+        // Without a configured limit there is nothing to cap at
+        assert_eq!(BotManager::parse_exec_options("--timeout 600 ls", 0, false).unwrap().0, 600);
+    }
 }
@@ -1,3 +1,4 @@
+use crate::types::Permission;
 use teloxide::utils::command::BotCommands;
 
 #[derive(BotCommands, Clone)]
@@ -5,12 +6,10 @@ use teloxide::utils::command::BotCommands;
 pub enum Command {
     #[command(description = "Show help")]
     Help,
-    #[command(description = "Request authorization code")]
-    AuthRequest,
-    #[command(description = "Authorize with access code")]
+    #[command(description = "Request an access code, or authorize with one")]
     Auth(String),
     #[command(description = "List directory contents")]
-    Ls,
+    Ls(String),
     #[command(description = "Change directory")]
     Cd(String),
     #[command(description = "Download file")]
@@ -19,4 +18,72 @@ pub enum Command {
     Exec(String),
     #[command(description = "Print working directory")]
     Pwd,
-}
\ No newline at end of file
+    #[command(description = "Show executed commands")]
+    History,
+    #[command(description = "Start an interactive shell")]
+    Shell,
+    #[command(description = "Close the interactive shell")]
+    Exit,
+    #[command(description = "Run command in the background")]
+    Bg(String),
+    #[command(description = "List running and finished jobs")]
+    Jobs,
+    #[command(description = "Stop a running command")]
+    Kill(String),
+    #[command(description = "Show the environment commands start with")]
+    Env(String),
+    #[command(description = "Set an environment variable for your commands")]
+    Setenv(String),
+    #[command(description = "Remove an environment variable for your commands")]
+    Unsetenv(String),
+    #[command(description = "List authorized users")]
+    Users,
+    #[command(description = "Revoke access of a user")]
+    Revoke(String),
+    #[command(description = "Give a user the next higher role")]
+    Promote(String),
+    #[command(description = "Give a user the next lower role")]
+    Demote(String),
+    #[command(description = "Create a one-time access code")]
+    Invite(String),
+    #[command(description = "Show recent audit log entries")]
+    Audit(String),
+    #[command(description = "End your authorization")]
+    Logout,
+    #[command(description = "Confirm a sensitive command with a TOTP code, or manage TOTP")]
+    Totp(String),
+}
+
+impl Command {
+    /// The permission needed to run the command, `None` for commands open to everyone.
+    pub fn required_permission(&self) -> Option<Permission> {
+        match self {
+            Command::Help | Command::Auth(_) | Command::Logout => None,
+            Command::Ls(_)
+            | Command::Cd(_)
+            | Command::Pwd
+            | Command::History
+            | Command::Totp(_) => {
+                Some(Permission::ViewFiles)
+            }
+            Command::Download(_) => Some(Permission::Download),
+            Command::Exec(_)
+            | Command::Bg(_)
+            | Command::Jobs
+            | Command::Kill(_) => Some(Permission::Exec),
+            // Variables like `LD_PRELOAD` or `PATH` change what a command runs without
+            // the exec policy seeing it, and the environment may hold secrets
+            Command::Env(_)
+            | Command::Setenv(_)
+            | Command::Unsetenv(_)
+            | Command::Shell
+            | Command::Exit => Some(Permission::Shell),
+            Command::Users
+            | Command::Revoke(_)
+            | Command::Promote(_)
+            | Command::Demote(_)
+            | Command::Invite(_) => Some(Permission::ManageUsers),
+            Command::Audit(_) => Some(Permission::ViewAudit),
+        }
+    }
+}
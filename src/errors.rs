@@ -1,12 +1,15 @@
 use std::fmt;
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum BotError {
     ConfigError(String),
     AuthError(String),
     FileError(String),
+    SandboxError(String),
     LogError(String),
     TelegramError(String),
+    ExecError(String),
     SerializationError(String),
 }
 
@@ -16,8 +19,10 @@ impl fmt::Display for BotError {
             BotError::ConfigError(msg) => write!(f, "Configuration error: {}", msg),
             BotError::AuthError(msg) => write!(f, "Authentication error: {}", msg),
             BotError::FileError(msg) => write!(f, "File error: {}", msg),
+            BotError::SandboxError(msg) => write!(f, "Access denied: {}", msg),
             BotError::LogError(msg) => write!(f, "Log error: {}", msg),
             BotError::TelegramError(msg) => write!(f, "Telegram error: {}", msg),
+            BotError::ExecError(msg) => write!(f, "Execution error: {}", msg),
             BotError::SerializationError(msg) => write!(f, "Serialization error: {}", msg),
         }
     }
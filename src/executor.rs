@@ -0,0 +1,340 @@
+use crate::errors::BotError;
+use crate::types::{BaseEnvironment, Config};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::Path;
+use std::process::{ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::process::{Child, Command};
+use tokio::task::JoinHandle;
+
+const READ_BUFFER_SIZE: usize = 4096;
+/// Output kept per stream for progress updates, enough to fill a message.
+const RECENT_OUTPUT_BYTES: usize = 16 * 1024;
+const TERMINATE_GRACE_PERIOD: Duration = Duration::from_secs(5);
+/// How long output is still read after the command exits. A process it left behind,
+/// e.g. through `setsid`, may keep the streams open indefinitely.
+const DRAIN_GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+/// The configured shell with the base environment and a session's variables on
+/// top, where `None` removes a variable. Callers add the remaining arguments.
+pub fn shell_command(config: &Config, environment: &HashMap<String, Option<String>>) -> Command {
+    let mut command = Command::new(&config.exec_shell);
+    if config.exec_login_shell {
+        command.arg("-l");
+    }
+
+    match &config.exec_environment {
+        BaseEnvironment::Inherit => {}
+        BaseEnvironment::Clear => {
+            command.env_clear();
+        }
+        BaseEnvironment::Explicit(variables) => {
+            command.env_clear().envs(variables);
+        }
+    }
+
+    for (name, value) in environment {
+        match value {
+            Some(value) => command.env(name, value),
+            None => command.env_remove(name),
+        };
+    }
+
+    command
+}
+
+/// The variables a command would start with, before any profile files run.
+pub fn effective_environment(
+    config: &Config,
+    environment: &HashMap<String, Option<String>>,
+) -> BTreeMap<String, String> {
+    let mut variables: BTreeMap<String, String> = match &config.exec_environment {
+        BaseEnvironment::Inherit => std::env::vars().collect(),
+        BaseEnvironment::Clear => BTreeMap::new(),
+        BaseEnvironment::Explicit(variables) => variables.clone().into_iter().collect(),
+    };
+
+    for (name, value) in environment {
+        match value {
+            Some(value) => variables.insert(name.clone(), value.clone()),
+            None => variables.remove(name),
+        };
+    }
+
+    variables
+}
+
+/// Output collected from a running process, shared between the reader tasks and the caller.
+/// Anything past the limit is dropped, so a runaway command can't exhaust memory.
+#[derive(Clone)]
+pub struct OutputBuffer {
+    data: Arc<Mutex<Vec<u8>>>,
+    /// The last bytes, kept even past the limit, so progress can be shown cheaply
+    recent: Arc<Mutex<VecDeque<u8>>>,
+    truncated: Arc<AtomicBool>,
+    limit: usize,
+}
+
+impl OutputBuffer {
+    fn with_limit(limit: usize) -> Self {
+        OutputBuffer {
+            data: Arc::new(Mutex::new(Vec::new())),
+            recent: Arc::new(Mutex::new(VecDeque::with_capacity(RECENT_OUTPUT_BYTES))),
+            truncated: Arc::new(AtomicBool::new(false)),
+            limit,
+        }
+    }
+
+    fn append(&self, bytes: &[u8]) {
+        if let Ok(mut data) = self.data.lock() {
+            let room = self.limit.saturating_sub(data.len());
+            if bytes.len() > room {
+                self.truncated.store(true, Ordering::Relaxed);
+            }
+            data.extend_from_slice(&bytes[..bytes.len().min(room)]);
+        }
+
+        if let Ok(mut recent) = self.recent.lock() {
+            let bytes = &bytes[bytes.len().saturating_sub(RECENT_OUTPUT_BYTES)..];
+            let excess = (recent.len() + bytes.len()).saturating_sub(RECENT_OUTPUT_BYTES);
+            recent.drain(..excess);
+            recent.extend(bytes);
+        }
+    }
+
+    /// The last few kilobytes, starting at a character boundary.
+    pub fn recent_contents(&self) -> String {
+        self.recent
+            .lock()
+            .map(|mut recent| {
+                let bytes = recent.make_contiguous();
+                // Skips UTF-8 continuation bytes left over from a cut character
+                let start = bytes
+                    .iter()
+                    .position(|byte| byte & 0xC0 != 0x80)
+                    .unwrap_or(bytes.len());
+                String::from_utf8_lossy(&bytes[start..]).to_string()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn is_truncated(&self) -> bool {
+        self.truncated.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes(&self) -> Vec<u8> {
+        self.data.lock().map(|data| data.clone()).unwrap_or_default()
+    }
+
+    pub fn contents(&self) -> String {
+        self.data
+            .lock()
+            .map(|data| String::from_utf8_lossy(&data).to_string())
+            .unwrap_or_default()
+    }
+}
+
+/// How a command ended, or how far it got, with both output streams.
+pub struct ExecResult {
+    pub stdout: String,
+    pub stderr: String,
+    /// Both streams in the order they arrived
+    pub combined: String,
+    pub exit_code: Option<i32>,
+    /// The signal that terminated the command, if it didn't exit by itself
+    pub signal: Option<i32>,
+    pub duration: Duration,
+    /// Whether output past the capture limit was dropped
+    pub truncated: bool,
+    /// Whether reading stopped while a process left behind still held the streams open
+    pub abandoned: bool,
+}
+
+impl ExecResult {
+    pub fn output_bytes(&self) -> usize {
+        self.stdout.len() + self.stderr.len()
+    }
+}
+
+pub struct RunningCommand {
+    child: Child,
+    process_group: u32,
+    /// Becomes [`ExecResult::combined`]
+    output: OutputBuffer,
+    stdout: OutputBuffer,
+    stderr: OutputBuffer,
+    readers: Vec<JoinHandle<()>>,
+    /// Set once the command has exited, to stop reading at
+    drain_deadline: Option<tokio::time::Instant>,
+    abandoned: bool,
+    started_at: Instant,
+}
+
+impl RunningCommand {
+    pub fn spawn(
+        command: &str,
+        current_dir: &Path,
+        environment: &HashMap<String, Option<String>>,
+        config: &Config,
+    ) -> Result<Self, BotError> {
+        let mut child = shell_command(config, environment)
+            .args(&config.exec_shell_args)
+            .arg(command)
+            .current_dir(current_dir)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .process_group(0)
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| BotError::ExecError(format!("Failed to spawn command: {}", e)))?;
+
+        let process_group = child
+            .id()
+            .ok_or_else(|| BotError::ExecError("Command exited before it could be tracked".to_string()))?;
+
+        let max_output_bytes = config.exec_max_output_bytes;
+        let output = OutputBuffer::with_limit(max_output_bytes.saturating_mul(2));
+        let stdout_buffer = OutputBuffer::with_limit(max_output_bytes);
+        let stderr_buffer = OutputBuffer::with_limit(max_output_bytes);
+        let mut readers = Vec::new();
+
+        if let Some(stdout) = child.stdout.take() {
+            readers.push(Self::spawn_reader(stdout, output.clone(), stdout_buffer.clone()));
+        }
+        if let Some(stderr) = child.stderr.take() {
+            readers.push(Self::spawn_reader(stderr, output.clone(), stderr_buffer.clone()));
+        }
+
+        Ok(RunningCommand {
+            child,
+            process_group,
+            output,
+            stdout: stdout_buffer,
+            stderr: stderr_buffer,
+            readers,
+            drain_deadline: None,
+            abandoned: false,
+            started_at: Instant::now(),
+        })
+    }
+
+    fn spawn_reader<R>(mut stream: R, output: OutputBuffer, stream_output: OutputBuffer) -> JoinHandle<()>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        tokio::spawn(async move {
+            let mut buffer = [0u8; READ_BUFFER_SIZE];
+            loop {
+                match stream.read(&mut buffer).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(read) => {
+                        output.append(&buffer[..read]);
+                        stream_output.append(&buffer[..read]);
+                    }
+                }
+            }
+        })
+    }
+
+    /// The most recent output only, cheap enough for frequent progress updates.
+    pub fn progress(&self) -> ExecResult {
+        ExecResult {
+            stdout: self.stdout.recent_contents(),
+            stderr: self.stderr.recent_contents(),
+            combined: self.output.recent_contents(),
+            exit_code: None,
+            signal: None,
+            duration: self.elapsed(),
+            truncated: false,
+            abandoned: false,
+        }
+    }
+
+    /// The output so far, along with the exit status once there is one.
+    pub fn result(&self, status: Option<ExitStatus>) -> ExecResult {
+        #[cfg(unix)]
+        let signal = {
+            use std::os::unix::process::ExitStatusExt;
+            status.and_then(|status| status.signal())
+        };
+        #[cfg(not(unix))]
+        let signal = None;
+
+        ExecResult {
+            stdout: self.stdout.contents(),
+            stderr: self.stderr.contents(),
+            combined: self.output.contents(),
+            exit_code: status.and_then(|status| status.code()),
+            signal,
+            duration: self.elapsed(),
+            truncated: self.stdout.is_truncated() || self.stderr.is_truncated(),
+            abandoned: self.abandoned,
+        }
+    }
+
+    pub fn stdout(&self) -> OutputBuffer {
+        self.stdout.clone()
+    }
+
+    pub fn stderr(&self) -> OutputBuffer {
+        self.stderr.clone()
+    }
+
+    /// The process group id, which equals the pid since the command leads its own group.
+    pub fn process_group(&self) -> u32 {
+        self.process_group
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
+    /// Waits for the process to exit and for both output streams to be drained,
+    /// giving up on the streams shortly after the exit. Safe to cancel and call
+    /// again, e.g. from a `tokio::select!` loop.
+    pub async fn wait(&mut self) -> Result<ExitStatus, BotError> {
+        let status = self
+            .child
+            .wait()
+            .await
+            .map_err(|e| BotError::ExecError(format!("Failed to wait for command: {}", e)))?;
+
+        let deadline = *self
+            .drain_deadline
+            .get_or_insert_with(|| tokio::time::Instant::now() + DRAIN_GRACE_PERIOD);
+
+        while let Some(reader) = self.readers.last_mut() {
+            if tokio::time::timeout_at(deadline, reader).await.is_err() {
+                for reader in self.readers.drain(..) {
+                    reader.abort();
+                }
+                self.abandoned = true;
+                break;
+            }
+            self.readers.pop();
+        }
+
+        Ok(status)
+    }
+}
+
+/// Sends SIGTERM to the whole process group, then SIGKILL if it is still alive after a grace period.
+pub async fn terminate_process_group(process_group: u32) {
+    let process_group = process_group as libc::pid_t;
+
+    unsafe {
+        libc::killpg(process_group, libc::SIGTERM);
+    }
+
+    tokio::time::sleep(TERMINATE_GRACE_PERIOD).await;
+
+    unsafe {
+        if libc::killpg(process_group, 0) == 0 {
+            libc::killpg(process_group, libc::SIGKILL);
+        }
+    }
+}
@@ -1,14 +1,22 @@
 use crate::errors::BotError;
-use crate::types::FileItem;
+use crate::types::{FileItem, FileKind, ListingOptions, SortOrder};
+use chrono::{DateTime, Local};
+use std::collections::HashMap;
+use std::ffi::CStr;
 use std::fs;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, OpenOptionsExt};
 use std::path::{Path, PathBuf};
 
+// Large enough for any passwd/group entry we expect; lookups fall back to the numeric id
+const NAME_BUFFER_SIZE: usize = 16 * 1024;
+
 pub struct FileManager {
     current_directory: PathBuf,
+    root_directory: Option<PathBuf>,
 }
 
 impl FileManager {
-    pub fn new(working_directory: &str) -> Result<Self, BotError> {
+    pub fn new(working_directory: &str, root_directory: Option<&str>) -> Result<Self, BotError> {
         let path = PathBuf::from(working_directory);
 
         if !path.exists() {
@@ -16,14 +24,43 @@ impl FileManager {
                 .map_err(|e| BotError::FileError(format!("Failed to create working directory: {}", e)))?;
         }
 
-        Ok(FileManager {
-            current_directory: path.canonicalize()
-                .map_err(|e| BotError::FileError(format!("Failed to canonicalize path: {}", e)))?,
-        })
+        let root_directory = root_directory
+            .map(|root| {
+                Path::new(root).canonicalize().map_err(|e| {
+                    BotError::FileError(format!("Failed to canonicalize root directory: {}", e))
+                })
+            })
+            .transpose()?;
+
+        let mut file_manager = FileManager {
+            current_directory: PathBuf::new(),
+            root_directory,
+        };
+        file_manager.current_directory = file_manager.resolve_path(&path)?;
+
+        Ok(file_manager)
+    }
+
+    /// Resolves symlinks and `..` components and rejects anything outside the root directory.
+    pub fn resolve_path(&self, path: &Path) -> Result<PathBuf, BotError> {
+        let resolved = self.current_directory.join(path).canonicalize().map_err(|e| {
+            BotError::FileError(format!("Failed to resolve {}: {}", path.display(), e))
+        })?;
+
+        match &self.root_directory {
+            Some(root) if !resolved.starts_with(root) => Err(BotError::SandboxError(format!(
+                "{} is outside of {}",
+                path.display(),
+                root.display()
+            ))),
+            _ => Ok(resolved),
+        }
     }
 
-    pub fn list_directory(&self) -> Result<Vec<FileItem>, BotError> {
+    pub fn list_directory(&self, options: &ListingOptions) -> Result<Vec<FileItem>, BotError> {
         let mut items = Vec::new();
+        let mut owners = HashMap::new();
+        let mut groups = HashMap::new();
 
         for entry in fs::read_dir(&self.current_directory)
             .map_err(|e| BotError::FileError(format!("Failed to read directory: {}", e)))? {
@@ -31,32 +68,125 @@ impl FileManager {
             let entry = entry
                 .map_err(|e| BotError::FileError(format!("Failed to read directory entry: {}", e)))?;
 
-            let path = entry.path();
             let name = entry.file_name().to_string_lossy().to_string();
-            let is_directory = path.is_dir();
+            if !options.all && name.starts_with('.') {
+                continue;
+            }
+
+            let path = entry.path();
+            let metadata = fs::symlink_metadata(&path)
+                .map_err(|e| BotError::FileError(format!("Failed to read metadata: {}", e)))?;
+            let file_type = metadata.file_type();
+
+            let kind = if file_type.is_symlink() {
+                FileKind::Symlink
+            } else if file_type.is_dir() {
+                FileKind::Directory
+            } else if file_type.is_socket() {
+                FileKind::Socket
+            } else if file_type.is_fifo() {
+                FileKind::Fifo
+            } else if file_type.is_block_device() {
+                FileKind::BlockDevice
+            } else if file_type.is_char_device() {
+                FileKind::CharDevice
+            } else {
+                FileKind::File
+            };
+
+            let owner = owners
+                .entry(metadata.uid())
+                .or_insert_with(|| Self::user_name(metadata.uid()))
+                .clone();
+            let group = groups
+                .entry(metadata.gid())
+                .or_insert_with(|| Self::group_name(metadata.gid()))
+                .clone();
 
             items.push(FileItem {
                 name,
+                is_directory: path.is_dir(),
+                size: metadata.len(),
+                kind,
+                mode: metadata.mode(),
+                owner,
+                group,
+                modified: metadata.modified().ok().map(DateTime::<Local>::from),
+                symlink_target: fs::read_link(&path).ok(),
                 path,
-                is_directory,
             });
         }
 
+        match options.sort {
+            // Directories first, then by name, so listings are stable between requests
+            SortOrder::Name => items.sort_by(|a, b| {
+                b.is_directory
+                    .cmp(&a.is_directory)
+                    .then_with(|| a.name.cmp(&b.name))
+            }),
+            SortOrder::Time => items.sort_by(|a, b| {
+                b.modified
+                    .cmp(&a.modified)
+                    .then_with(|| a.name.cmp(&b.name))
+            }),
+            SortOrder::Size => {
+                items.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.name.cmp(&b.name)))
+            }
+        }
+
         Ok(items)
     }
 
+    fn user_name(uid: u32) -> String {
+        let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut buffer = vec![0 as libc::c_char; NAME_BUFFER_SIZE];
+        let mut result = std::ptr::null_mut();
+
+        let status = unsafe {
+            libc::getpwuid_r(uid, &mut passwd, buffer.as_mut_ptr(), buffer.len(), &mut result)
+        };
+
+        if status != 0 || result.is_null() {
+            return uid.to_string();
+        }
+
+        unsafe { CStr::from_ptr(passwd.pw_name) }
+            .to_string_lossy()
+            .to_string()
+    }
+
+    fn group_name(gid: u32) -> String {
+        let mut group: libc::group = unsafe { std::mem::zeroed() };
+        let mut buffer = vec![0 as libc::c_char; NAME_BUFFER_SIZE];
+        let mut result = std::ptr::null_mut();
+
+        let status = unsafe {
+            libc::getgrgid_r(gid, &mut group, buffer.as_mut_ptr(), buffer.len(), &mut result)
+        };
+
+        if status != 0 || result.is_null() {
+            return gid.to_string();
+        }
+
+        unsafe { CStr::from_ptr(group.gr_name) }
+            .to_string_lossy()
+            .to_string()
+    }
+
     pub fn change_directory(&mut self, path: &str) -> Result<(), BotError> {
-        let new_path = if path == ".." {
-            self.current_directory.parent()
-                .map(|p| p.to_path_buf())
-                .unwrap_or_else(|| self.current_directory.clone())
+        // Like in a chroot, going up from the root directory stays there
+        let new_path = if path == ".." && !self.can_go_up() {
+            self.current_directory.clone()
         } else {
             self.current_directory.join(path)
         };
 
+        self.set_current_directory(&new_path)
+    }
+
+    pub fn set_current_directory(&mut self, new_path: &Path) -> Result<(), BotError> {
         if new_path.is_dir() {
-            self.current_directory = new_path.canonicalize()
-                .map_err(|e| BotError::FileError(format!("Failed to canonicalize path: {}", e)))?;
+            self.current_directory = self.resolve_path(new_path)?;
             Ok(())
         } else {
             Err(BotError::FileError("Directory does not exist".to_string()))
@@ -67,15 +197,170 @@ impl FileManager {
         &self.current_directory
     }
 
-    pub fn get_file_path(&self, filename: &str) -> PathBuf {
-        self.current_directory.join(filename)
+    pub fn can_go_up(&self) -> bool {
+        match &self.root_directory {
+            Some(root) => self.current_directory != *root,
+            None => self.current_directory.parent().is_some(),
+        }
     }
 
-    pub fn file_exists(&self, filename: &str) -> bool {
-        self.current_directory.join(filename).exists()
+    pub fn get_file_path(&self, filename: &str) -> Result<PathBuf, BotError> {
+        self.resolve_path(Path::new(filename))
     }
 
-    pub fn is_file(&self, filename: &str) -> bool {
-        self.current_directory.join(filename).is_file()
+    /// Resolves where an uploaded file should be stored. Only plain file names are
+    /// accepted so uploads always land in the current directory, which is resolved
+    /// again in case it was replaced by a symlink since.
+    pub fn get_upload_path(&self, filename: &str) -> Result<PathBuf, BotError> {
+        let is_plain_name = Path::new(filename)
+            .file_name()
+            .is_some_and(|name| name == filename);
+
+        if !is_plain_name {
+            return Err(BotError::FileError(format!("Invalid file name: {}", filename)));
+        }
+
+        Ok(self.resolve_path(&self.current_directory)?.join(filename))
+    }
+
+    /// Opens a file of the current directory for writing an upload into. A symlink
+    /// in its place is refused rather than followed, it could point out of the root.
+    pub fn open_upload_file(path: &Path) -> Result<fs::File, BotError> {
+        fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(path)
+            .map_err(|e| BotError::FileError(format!("Failed to create file: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    /// A temp directory holding `root/sub` and `outside/secret`.
+    struct Sandbox {
+        base: PathBuf,
+    }
+
+    impl Sandbox {
+        fn new() -> Self {
+            let base = std::env::temp_dir().join(format!("telebash-fm-{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(base.join("root/sub")).unwrap();
+            fs::create_dir_all(base.join("outside")).unwrap();
+            fs::write(base.join("outside/secret"), "secret").unwrap();
+            Sandbox {
+                base: base.canonicalize().unwrap(),
+            }
+        }
+
+        fn path(&self, path: &str) -> PathBuf {
+            self.base.join(path)
+        }
+
+        fn jailed(&self) -> FileManager {
+            let root = self.path("root");
+            FileManager::new(root.to_str().unwrap(), root.to_str()).unwrap()
+        }
+    }
+
+    impl Drop for Sandbox {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.base);
+        }
+    }
+
+    fn is_outside_root(result: Result<PathBuf, BotError>) -> bool {
+        matches!(result, Err(BotError::SandboxError(_)))
+    }
+
+    #[test]
+    fn resolve_path_rejects_escapes_from_the_root() {
+        let sandbox = Sandbox::new();
+        let file_manager = sandbox.jailed();
+
+        assert!(is_outside_root(file_manager.resolve_path(Path::new(".."))));
+        assert!(is_outside_root(file_manager.resolve_path(Path::new("sub/../../outside"))));
+        assert!(is_outside_root(file_manager.resolve_path(&sandbox.path("outside/secret"))));
+        assert!(is_outside_root(file_manager.resolve_path(Path::new("/"))));
+        assert_eq!(
+            file_manager.resolve_path(Path::new("sub/..")).unwrap(),
+            sandbox.path("root")
+        );
+        assert_eq!(
+            file_manager.resolve_path(&sandbox.path("root/sub")).unwrap(),
+            sandbox.path("root/sub")
+        );
+    }
+
+    #[test]
+    fn resolve_path_follows_symlinks_before_checking() {
+        let sandbox = Sandbox::new();
+        symlink(sandbox.path("outside"), sandbox.path("root/escape")).unwrap();
+        symlink(sandbox.path("outside/secret"), sandbox.path("root/secret")).unwrap();
+        symlink(sandbox.path("root/sub"), sandbox.path("root/inner")).unwrap();
+        let mut file_manager = sandbox.jailed();
+
+        assert!(is_outside_root(file_manager.resolve_path(Path::new("escape"))));
+        assert!(is_outside_root(file_manager.resolve_path(Path::new("escape/secret"))));
+        assert!(is_outside_root(file_manager.get_file_path("secret")));
+        assert!(file_manager.change_directory("escape").is_err());
+        assert_eq!(file_manager.resolve_path(Path::new("inner")).unwrap(), sandbox.path("root/sub"));
+    }
+
+    #[test]
+    fn change_directory_stays_in_the_root() {
+        let sandbox = Sandbox::new();
+        let mut file_manager = sandbox.jailed();
+
+        assert!(!file_manager.can_go_up());
+        file_manager.change_directory("..").unwrap();
+        assert_eq!(file_manager.get_current_directory(), sandbox.path("root"));
+
+        file_manager.change_directory("sub").unwrap();
+        assert!(file_manager.can_go_up());
+        assert!(file_manager.change_directory("../..").is_err());
+        assert_eq!(file_manager.get_current_directory(), sandbox.path("root/sub"));
+    }
+
+    #[test]
+    fn get_upload_path_only_accepts_plain_names() {
+        let sandbox = Sandbox::new();
+        let file_manager = sandbox.jailed();
+
+        for name in ["../x", "sub/x", "/etc/passwd", "..", ".", ""] {
+            assert!(file_manager.get_upload_path(name).is_err(), "{} was accepted", name);
+        }
+        assert_eq!(file_manager.get_upload_path("x.txt").unwrap(), sandbox.path("root/x.txt"));
+    }
+
+    #[test]
+    fn get_upload_path_rejects_a_current_directory_swapped_for_a_symlink() {
+        let sandbox = Sandbox::new();
+        let mut file_manager = sandbox.jailed();
+
+        file_manager.change_directory("sub").unwrap();
+        fs::remove_dir(sandbox.path("root/sub")).unwrap();
+        symlink(sandbox.path("outside"), sandbox.path("root/sub")).unwrap();
+
+        assert!(file_manager.get_upload_path("x.txt").is_err());
+    }
+
+    #[test]
+    fn open_upload_file_refuses_symlinks() {
+        let sandbox = Sandbox::new();
+        let partial = sandbox.path("root/x.txt.part");
+        symlink(sandbox.path("outside/secret"), &partial).unwrap();
+
+        assert!(FileManager::open_upload_file(&partial).is_err());
+        assert_eq!(fs::read_to_string(sandbox.path("outside/secret")).unwrap(), "secret");
+
+        fs::remove_file(&partial).unwrap();
+        fs::write(&partial, "leftover").unwrap();
+        FileManager::open_upload_file(&partial).unwrap();
+        assert_eq!(fs::read_to_string(&partial).unwrap(), "");
     }
 }
\ No newline at end of file
@@ -0,0 +1,185 @@
+/// Telegram's limit for the visible text of one message, in UTF-16 code units.
+pub const MESSAGE_LIMIT: usize = 4096;
+
+/// Every character MarkdownV2 reserves outside of code entities.
+const MARKDOWN_V2_RESERVED: &[char] = &[
+    '_', '*', '[', ']', '(', ')', '~', '`', '>', '#', '+', '-', '=', '|', '{', '}', '.', '!', '\\',
+];
+
+pub fn escape_markdown_v2(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if MARKDOWN_V2_RESERVED.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Inside `pre` and `code` entities only backticks and backslashes need escaping.
+pub fn escape_markdown_v2_code(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c == '`' || c == '\\' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// A MarkdownV2 code block; embedded backticks can't end it early.
+pub fn markdown_v2_code_block(text: &str) -> String {
+    format!("```\n{}\n```", escape_markdown_v2_code(text))
+}
+
+pub fn html_code_block(text: &str) -> String {
+    format!("<pre>{}</pre>", escape_html(text))
+}
+
+pub fn html_inline_code(text: &str) -> String {
+    format!("<code>{}</code>", escape_html(text))
+}
+
+/// Length of the text as Telegram counts it.
+pub fn text_len(text: &str) -> usize {
+    text.encode_utf16().count()
+}
+
+/// Shortens the text to at most `max_len`, ending it with an ellipsis.
+pub fn truncate(text: &str, max_len: usize) -> String {
+    if text_len(text) <= max_len {
+        return text.to_string();
+    }
+
+    // Leaves room for the ellipsis
+    format!("{}…", head(text, max_len.saturating_sub(1)))
+}
+
+/// The beginning of the text that fits into `max_len`.
+pub fn head(text: &str, max_len: usize) -> &str {
+    let mut len = 0;
+    let mut end = 0;
+
+    for (index, c) in text.char_indices() {
+        if len + c.len_utf16() > max_len {
+            break;
+        }
+        len += c.len_utf16();
+        end = index + c.len_utf8();
+    }
+
+    &text[..end]
+}
+
+/// The end of the text that fits into `max_len`.
+pub fn tail(text: &str, max_len: usize) -> &str {
+    let mut len = 0;
+    let mut start = text.len();
+
+    for (index, c) in text.char_indices().rev() {
+        if len + c.len_utf16() > max_len {
+            break;
+        }
+        len += c.len_utf16();
+        start = index;
+    }
+
+    &text[start..]
+}
+
+/// Splits plain text into parts of at most `max_len`, preferring line breaks and
+/// never cutting a character in half. Blank parts are left out, as Telegram
+/// rejects empty messages, so blank text gives no parts at all.
+pub fn split_message(text: &str, max_len: usize) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut rest = text;
+
+    while text_len(rest) > max_len {
+        let mut len = 0;
+        let mut cut = 0;
+        let mut last_newline = None;
+
+        for (index, c) in rest.char_indices() {
+            if len + c.len_utf16() > max_len {
+                break;
+            }
+            len += c.len_utf16();
+            cut = index + c.len_utf8();
+            if c == '\n' {
+                last_newline = Some(cut);
+            }
+        }
+
+        // A character wider than the limit still has to go somewhere, whole
+        let first_len = rest.chars().next().map_or(0, char::len_utf8);
+        let cut = last_newline.unwrap_or(cut).max(first_len);
+        let (part, remainder) = rest.split_at(cut);
+        parts.push(part.trim_end_matches('\n').to_string());
+        // Blank lines at the cut would only start the next message
+        rest = remainder.trim_start_matches('\n');
+    }
+    parts.push(rest.to_string());
+
+    parts.retain(|part| !part.trim().is_empty());
+    parts
+}
+
+/// Splits text into HTML code blocks that each fit into one message.
+pub fn split_html_code_blocks(text: &str, max_len: usize) -> Vec<String> {
+    split_message(text, max_len)
+        .iter()
+        .map(|part| html_code_block(part))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_message_respects_utf16_length() {
+        // Each emoji is a surrogate pair, two units, so the cut can't fall inside one
+        let text = "a😀😀😀";
+        let parts = split_message(text, 4);
+        assert_eq!(parts, vec!["a😀", "😀😀"]);
+        assert!(parts.iter().all(|part| text_len(part) <= 4));
+
+        let parts = split_message(&"я".repeat(5000), MESSAGE_LIMIT);
+        assert_eq!(parts.len(), 2);
+        assert_eq!(text_len(&parts[0]), MESSAGE_LIMIT);
+    }
+
+    #[test]
+    fn split_message_prefers_newlines() {
+        assert_eq!(split_message("abc\ndefgh", 8), vec!["abc", "defgh"]);
+        assert_eq!(split_message("abcdefgh", 5), vec!["abcde", "fgh"]);
+        assert_eq!(split_message("short", 10), vec!["short"]);
+    }
+
+    #[test]
+    fn split_message_leaves_out_empty_parts() {
+        assert!(split_message("", 10).is_empty());
+        assert!(split_message("\n\n\n", 10).is_empty());
+        assert_eq!(split_message(&format!("a{}b", "\n".repeat(20)), 10), vec!["a", "b"]);
+        // Wider than the limit, still sent whole rather than split or looping
+        assert_eq!(split_message("😀", 1), vec!["😀"]);
+    }
+
+    #[test]
+    fn escape_markdown_v2_escapes_every_reserved_character() {
+        let reserved = "_*[]()~`>#+-=|{}.!\\";
+        let escaped = escape_markdown_v2(reserved);
+        let expected: String = reserved.chars().flat_map(|c| ['\\', c]).collect();
+        assert_eq!(escaped, expected);
+        assert_eq!(escape_markdown_v2("plain text 123"), "plain text 123");
+    }
+}
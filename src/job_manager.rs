@@ -0,0 +1,95 @@
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+const MAX_FINISHED_JOBS: usize = 50;
+
+pub enum JobState {
+    Running,
+    Finished(String),
+}
+
+pub struct Job {
+    pub id: u64,
+    pub user_id: i64,
+    pub command: String,
+    pub process_group: u32,
+    pub started_at: Instant,
+    pub background: bool,
+    pub state: JobState,
+}
+
+pub struct JobManager {
+    jobs: BTreeMap<u64, Job>,
+    next_id: u64,
+}
+
+impl JobManager {
+    pub fn new() -> Self {
+        JobManager {
+            jobs: BTreeMap::new(),
+            next_id: 1,
+        }
+    }
+
+    pub fn register(
+        &mut self,
+        user_id: i64,
+        command: &str,
+        process_group: u32,
+        background: bool,
+    ) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.jobs.insert(
+            id,
+            Job {
+                id,
+                user_id,
+                command: command.to_string(),
+                process_group,
+                started_at: Instant::now(),
+                background,
+                state: JobState::Running,
+            },
+        );
+        id
+    }
+
+    pub fn remove(&mut self, id: u64) {
+        self.jobs.remove(&id);
+    }
+
+    /// Keeps a finished background job around so it still shows up in `/jobs`.
+    pub fn finish(&mut self, id: u64, summary: String) {
+        if let Some(job) = self.jobs.get_mut(&id) {
+            job.state = JobState::Finished(summary);
+        }
+
+        let finished: Vec<u64> = self
+            .jobs
+            .values()
+            .filter(|job| matches!(job.state, JobState::Finished(_)))
+            .map(|job| job.id)
+            .collect();
+
+        // Ids are increasing, so the oldest finished jobs come first
+        for id in finished.iter().take(finished.len().saturating_sub(MAX_FINISHED_JOBS)) {
+            self.jobs.remove(id);
+        }
+    }
+
+    /// Looks up a job of the user that is still running.
+    pub fn get_user_job(&self, user_id: i64, id: u64) -> Option<&Job> {
+        self.jobs
+            .get(&id)
+            .filter(|job| job.user_id == user_id && matches!(job.state, JobState::Running))
+    }
+
+    pub fn get_user_jobs(&self, user_id: i64) -> Vec<&Job> {
+        self.jobs
+            .values()
+            .filter(|job| job.user_id == user_id)
+            .collect()
+    }
+}
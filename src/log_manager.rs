@@ -1,22 +1,187 @@
 use crate::errors::BotError;
-use log::{LevelFilter, Record};
+use crate::types::{AuditEntry, Config};
+use chrono::{DateTime, Local, NaiveDate};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::LevelFilter;
 use simple_logger::SimpleLogger;
-use std::fs::OpenOptions;
-use std::io::Write;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
+use std::thread::JoinHandle;
+
+/// Rotated audit logs `/audit` looks through at most, so a query for a user with
+/// few entries doesn't decompress months of logs.
+const MAX_SEARCHED_AUDIT_FILES: usize = 14;
+
+/// When to move a log file aside and how many old ones to keep.
+#[derive(Clone)]
+struct RotationPolicy {
+    /// Rotate once the file would grow past this size, 0 disables it.
+    max_size: u64,
+    daily: bool,
+    retained_files: usize,
+    compress: bool,
+}
+
+/// An append-only log file that rotates to `<path>.1`, `<path>.2`, ... (with a
+/// `.gz` suffix when compressed), the highest number being the oldest.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    opened_on: NaiveDate,
+    policy: RotationPolicy,
+    /// Compresses the last rotated file in the background, so writers don't wait.
+    compressing: Option<JoinHandle<()>>,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, policy: RotationPolicy) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let metadata = file.metadata()?;
+
+        // A file last written to yesterday is rotated on the first write today
+        let opened_on = metadata
+            .modified()
+            .map(|modified| DateTime::<Local>::from(modified).date_naive())
+            .unwrap_or_else(|_| Local::now().date_naive());
+
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            file,
+            size: metadata.len(),
+            opened_on,
+            policy,
+            compressing: None,
+        })
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        let too_large = self.policy.max_size > 0
+            && self.size > 0
+            && self.size + data.len() as u64 > self.policy.max_size;
+        let new_day = self.policy.daily && Local::now().date_naive() != self.opened_on;
+
+        if too_large || new_day {
+            self.rotate()?;
+        }
+
+        self.file.write_all(data)?;
+        self.file.flush()?;
+        self.size += data.len() as u64;
+        Ok(())
+    }
+
+    /// Reopens the path, for when something else moved the file away.
+    fn reopen(&mut self) -> io::Result<()> {
+        let reopened = Self::open(&self.path, self.policy.clone())?;
+        self.file = reopened.file;
+        self.size = reopened.size;
+        self.opened_on = reopened.opened_on;
+        Ok(())
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        self.numbered_path(index, self.policy.compress)
+    }
+
+    fn numbered_path(&self, index: usize, compressed: bool) -> PathBuf {
+        let mut name = self.path.as_os_str().to_os_string();
+        name.push(format!(".{}", index));
+        if compressed {
+            name.push(".gz");
+        }
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let retained = self.policy.retained_files;
+        // The previous file has to be in place before it's shifted along
+        self.wait_for_compression();
+
+        if retained == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let plain = self.numbered_path(1, false);
+            // Left over when the bot stopped while compressing it
+            if self.policy.compress && plain.exists() {
+                Self::compress(&plain, &self.rotated_path(1))?;
+            }
+
+            // Missing files are fine, there may not be that many yet
+            let _ = fs::remove_file(self.rotated_path(retained));
+            for index in (1..retained).rev() {
+                let _ = fs::rename(self.rotated_path(index), self.rotated_path(index + 1));
+            }
+
+            fs::rename(&self.path, &plain)?;
+            if self.policy.compress {
+                // `/audit` reads the plain file until the compressed one is complete
+                let target = self.rotated_path(1);
+                self.compressing = Some(std::thread::spawn(move || {
+                    if let Err(e) = Self::compress(&plain, &target) {
+                        log::error!("Failed to compress {}: {}", plain.display(), e);
+                    }
+                }));
+            }
+        }
+
+        self.reopen()?;
+        self.opened_on = Local::now().date_naive();
+        Ok(())
+    }
+
+    fn wait_for_compression(&mut self) {
+        if let Some(compressing) = self.compressing.take() {
+            let _ = compressing.join();
+        }
+    }
+
+    /// Replaces `source` with its compressed copy at `target`, which only shows up
+    /// once it's complete.
+    fn compress(source: &Path, target: &Path) -> io::Result<()> {
+        let mut partial = target.as_os_str().to_os_string();
+        partial.push(".part");
+
+        let mut input = File::open(source)?;
+        let mut encoder = GzEncoder::new(File::create(&partial)?, Compression::default());
+        io::copy(&mut input, &mut encoder)?;
+        encoder.finish()?.sync_all()?;
+
+        fs::rename(&partial, target)?;
+        fs::remove_file(source)
+    }
+}
 
 pub struct LogManager {
-    file: Mutex<std::fs::File>,
+    file: Mutex<RotatingFile>,
+    audit_file: Mutex<RotatingFile>,
+    audit_file_path: String,
 }
 
 impl LogManager {
-    pub fn new(log_file_path: &str) -> Result<Self, BotError> {
-        let file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(log_file_path)
+    pub fn new(config: &Config) -> Result<Self, BotError> {
+        let policy = RotationPolicy {
+            max_size: config.log_max_size_bytes,
+            daily: config.log_rotate_daily,
+            retained_files: config.log_retained_files,
+            compress: config.log_compress,
+        };
+        let audit_policy = RotationPolicy {
+            retained_files: config.audit_log_retained_files,
+            ..policy.clone()
+        };
+
+        let file = RotatingFile::open(Path::new(&config.log_file_path), policy)
             .map_err(|e| BotError::LogError(format!("Failed to open log file: {}", e)))?;
 
+        let audit_file = RotatingFile::open(Path::new(&config.audit_log_path), audit_policy)
+            .map_err(|e| BotError::LogError(format!("Failed to open audit log: {}", e)))?;
+
         SimpleLogger::new()
             .with_level(LevelFilter::Info)
             .init()
@@ -24,6 +189,8 @@ impl LogManager {
 
         Ok(LogManager {
             file: Mutex::new(file),
+            audit_file: Mutex::new(audit_file),
+            audit_file_path: config.audit_log_path.clone(),
         })
     }
 
@@ -36,9 +203,267 @@ impl LogManager {
         file_guard.write_all(log_entry.as_bytes())
             .map_err(|e| BotError::LogError(format!("Failed to write to log file: {}", e)))?;
 
-        file_guard.flush()
-            .map_err(|e| BotError::LogError(format!("Failed to flush log file: {}", e)))?;
+        Ok(())
+    }
+
+    /// Reopens both log files, so external tools like logrotate can move them
+    /// away and signal the bot with SIGHUP.
+    pub fn reopen(&self) -> Result<(), BotError> {
+        self.file.lock()
+            .map_err(|e| BotError::LogError(format!("Failed to lock log file: {}", e)))?
+            .reopen()
+            .map_err(|e| BotError::LogError(format!("Failed to reopen log file: {}", e)))?;
+
+        self.audit_file.lock()
+            .map_err(|e| BotError::LogError(format!("Failed to lock audit log: {}", e)))?
+            .reopen()
+            .map_err(|e| BotError::LogError(format!("Failed to reopen audit log: {}", e)))?;
+
+        Ok(())
+    }
+
+    /// Appends the entry to the audit log as one JSON line.
+    pub fn audit(&self, entry: &AuditEntry) -> Result<(), BotError> {
+        let mut line = serde_json::to_string(entry)
+            .map_err(|e| BotError::SerializationError(e.to_string()))?;
+        line.push('\n');
+
+        let mut file_guard = self.audit_file.lock()
+            .map_err(|e| BotError::LogError(format!("Failed to lock audit log: {}", e)))?;
+
+        file_guard.write_all(line.as_bytes())
+            .map_err(|e| BotError::LogError(format!("Failed to write to audit log: {}", e)))?;
 
         Ok(())
     }
-}
\ No newline at end of file
+
+    /// Returns the last `limit` audit entries, oldest first, optionally only those
+    /// of one user, given by id or username. The most recent rotated files are
+    /// searched too, newest first, until enough entries are found.
+    ///
+    /// Reads without holding the audit log, so writes go on meanwhile; run it off
+    /// the async runtime, as decompressing can take a while.
+    pub fn recent_audit_entries(&self, limit: usize, user: Option<&str>) -> Result<Vec<AuditEntry>, BotError> {
+        let user = user.map(|user| user.trim_start_matches('@'));
+        let mut entries = VecDeque::with_capacity(limit);
+
+        for index in 0..=MAX_SEARCHED_AUDIT_FILES {
+            if entries.len() >= limit {
+                break;
+            }
+            let Some(reader) = self.open_audit_file(index)? else {
+                break;
+            };
+
+            // Older files go in front of what was already found
+            let older = Self::read_audit_entries(reader, limit - entries.len(), user);
+            for entry in older.into_iter().rev() {
+                entries.push_front(entry);
+            }
+        }
+
+        Ok(entries.into())
+    }
+
+    /// Opens the current audit log for index 0, otherwise the rotated file with that
+    /// number, compressed or not. `None` once there are no more files.
+    fn open_audit_file(&self, index: usize) -> Result<Option<Box<dyn BufRead>>, BotError> {
+        let open_error = |e: io::Error| BotError::LogError(format!("Failed to open audit log: {}", e));
+
+        if index == 0 {
+            // Missing for a moment while it is being rotated
+            return match File::open(&self.audit_file_path) {
+                Ok(file) => Ok(Some(Box::new(BufReader::new(file)))),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Some(Box::new(io::empty()))),
+                Err(e) => Err(open_error(e)),
+            };
+        }
+
+        let path = format!("{}.{}", self.audit_file_path, index);
+        let compressed_path = format!("{}.gz", path);
+
+        if Path::new(&compressed_path).exists() {
+            let file = File::open(&compressed_path).map_err(open_error)?;
+            Ok(Some(Box::new(BufReader::new(GzDecoder::new(file)))))
+        } else if Path::new(&path).exists() {
+            let file = File::open(&path).map_err(open_error)?;
+            Ok(Some(Box::new(BufReader::new(file))))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// The last `limit` entries of one file that belong to the user, if one is given.
+    fn read_audit_entries(reader: Box<dyn BufRead>, limit: usize, user: Option<&str>) -> VecDeque<AuditEntry> {
+        let mut entries = VecDeque::with_capacity(limit);
+
+        // Stops at the first unreadable line, e.g. where a file is still being compressed
+        for line in reader.lines() {
+            let Ok(line) = line else {
+                break;
+            };
+
+            // Skips lines that were cut short, e.g. by a full disk
+            let Ok(entry) = serde_json::from_str::<AuditEntry>(&line) else {
+                continue;
+            };
+
+            let matches = user.is_none_or(|user| {
+                entry.user_id.to_string() == user || entry.username.as_deref() == Some(user)
+            });
+            if !matches {
+                continue;
+            }
+
+            if entries.len() == limit {
+                entries.pop_front();
+            }
+            entries.push_back(entry);
+        }
+
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    /// A temp directory, removed again at the end of the test.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            let path = std::env::temp_dir().join(format!("telebash-logs-{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+
+        fn path(&self, name: &str) -> PathBuf {
+            self.0.join(name)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn policy(max_size: u64, retained_files: usize, compress: bool) -> RotationPolicy {
+        RotationPolicy {
+            max_size,
+            daily: false,
+            retained_files,
+            compress,
+        }
+    }
+
+    fn read_gzip(path: &Path) -> String {
+        let mut content = String::new();
+        GzDecoder::new(File::open(path).unwrap())
+            .read_to_string(&mut content)
+            .unwrap();
+        content
+    }
+
+    #[test]
+    fn size_rotation_shifts_files_and_drops_the_oldest() {
+        let dir = TempDir::new();
+        let path = dir.path("bot.log");
+        let mut file = RotatingFile::open(&path, policy(4, 2, false)).unwrap();
+
+        for line in ["aaa\n", "bbb\n", "ccc\n", "ddd\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "ddd\n");
+        assert_eq!(fs::read_to_string(dir.path("bot.log.1")).unwrap(), "ccc\n");
+        assert_eq!(fs::read_to_string(dir.path("bot.log.2")).unwrap(), "bbb\n");
+        assert!(!dir.path("bot.log.3").exists());
+    }
+
+    #[test]
+    fn nothing_is_kept_without_retained_files() {
+        let dir = TempDir::new();
+        let path = dir.path("bot.log");
+        let mut file = RotatingFile::open(&path, policy(4, 0, false)).unwrap();
+
+        file.write_all(b"aaa\n").unwrap();
+        file.write_all(b"bbb\n").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "bbb\n");
+        assert!(!dir.path("bot.log.1").exists());
+    }
+
+    #[test]
+    fn rotated_files_are_compressed() {
+        let dir = TempDir::new();
+        let path = dir.path("bot.log");
+        let mut file = RotatingFile::open(&path, policy(4, 2, true)).unwrap();
+
+        for line in ["aaa\n", "bbb\n", "ccc\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        file.wait_for_compression();
+
+        assert_eq!(read_gzip(&dir.path("bot.log.1.gz")), "bbb\n");
+        assert_eq!(read_gzip(&dir.path("bot.log.2.gz")), "aaa\n");
+        assert!(!dir.path("bot.log.1").exists());
+        assert!(!dir.path("bot.log.1.gz.part").exists());
+    }
+
+    #[test]
+    fn daily_rotation_happens_on_the_first_write_of_a_day() {
+        let dir = TempDir::new();
+        let path = dir.path("bot.log");
+        let mut file = RotatingFile::open(&path, RotationPolicy { daily: true, ..policy(0, 2, false) }).unwrap();
+
+        file.write_all(b"today\n").unwrap();
+        file.write_all(b"still today\n").unwrap();
+        assert!(!dir.path("bot.log.1").exists());
+
+        file.opened_on = file.opened_on.pred_opt().unwrap();
+        file.write_all(b"tomorrow\n").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "tomorrow\n");
+        assert_eq!(fs::read_to_string(dir.path("bot.log.1")).unwrap(), "today\nstill today\n");
+        assert_eq!(file.opened_on, Local::now().date_naive());
+    }
+
+    #[test]
+    fn recent_audit_entries_reads_rotated_and_compressed_files() {
+        let dir = TempDir::new();
+        let path = dir.path("audit.log");
+        let lines = |users: &[i64]| -> String {
+            users
+                .iter()
+                .map(|user_id| {
+                    let entry = AuditEntry::new(*user_id, Some("someone"), *user_id, "ls", "");
+                    format!("{}\n", serde_json::to_string(&entry).unwrap())
+                })
+                .collect()
+        };
+
+        let mut encoder = GzEncoder::new(File::create(dir.path("audit.log.2.gz")).unwrap(), Compression::default());
+        encoder.write_all(lines(&[1, 2]).as_bytes()).unwrap();
+        encoder.finish().unwrap();
+        fs::write(dir.path("audit.log.1"), lines(&[3, 1])).unwrap();
+        fs::write(&path, lines(&[5, 6])).unwrap();
+
+        let log_manager = LogManager {
+            file: Mutex::new(RotatingFile::open(&dir.path("bot.log"), policy(0, 0, false)).unwrap()),
+            audit_file: Mutex::new(RotatingFile::open(&path, policy(0, 2, true)).unwrap()),
+            audit_file_path: path.display().to_string(),
+        };
+        let user_ids = |entries: Vec<AuditEntry>| -> Vec<i64> {
+            entries.iter().map(|entry| entry.user_id).collect()
+        };
+
+        assert_eq!(user_ids(log_manager.recent_audit_entries(5, None).unwrap()), vec![2, 3, 1, 5, 6]);
+        assert_eq!(user_ids(log_manager.recent_audit_entries(10, Some("1")).unwrap()), vec![1, 1]);
+        assert_eq!(user_ids(log_manager.recent_audit_entries(1, Some("1")).unwrap()), vec![1]);
+        assert_eq!(log_manager.recent_audit_entries(10, Some("@someone")).unwrap().len(), 6);
+    }
+}
@@ -6,14 +6,24 @@ mod auth_manager;
 mod log_manager;
 mod file_manager;
 mod config_manager;
+mod session_manager;
+mod executor;
+mod pty_shell;
+mod job_manager;
+mod attachment;
+mod policy;
+mod totp;
+mod formatting;
+mod ansi;
 
 use crate::config_manager::ConfigManager;
 use crate::bot::BotManager;
 use crate::auth_manager::AuthManager;
-use crate::file_manager::FileManager;
 use crate::log_manager::LogManager;
+use crate::session_manager::SessionManager;
 use crate::errors::BotError;
 use std::env;
+use std::time::Duration;
 
 #[tokio::main]
 async fn main() -> Result<(), BotError> {
@@ -29,9 +39,13 @@ async fn main() -> Result<(), BotError> {
     let config = ConfigManager::load_config(config_path)?;
 
     // Initialize managers
-    let auth_manager = AuthManager::new(&config.auth_file_path)?;
-    let file_manager = FileManager::new(&config.working_directory)?;
-    let log_manager = LogManager::new(&config.log_file_path)?;
+    let auth_manager = AuthManager::new(&config)?;
+    let session_manager = SessionManager::new(
+        &config.working_directory,
+        config.root_directory.as_deref(),
+        Duration::from_secs(config.session_idle_timeout_secs),
+    )?;
+    let log_manager = LogManager::new(&config)?;
 
     // Log startup
     log_manager.log(
@@ -40,7 +54,7 @@ async fn main() -> Result<(), BotError> {
     )?;
 
     // Create and run bot
-    let bot_manager = BotManager::new(&config, auth_manager, file_manager, log_manager)?;
+    let bot_manager = BotManager::new(&config, auth_manager, session_manager, log_manager)?;
 
     println!("Bot is running...");
     bot_manager.run().await?;
@@ -0,0 +1,807 @@
+use crate::errors::BotError;
+use crate::types::{PolicyAction, PolicyConfig, PolicyRule, Role};
+use regex::Regex;
+use std::fmt;
+use std::path::Path;
+
+/// Characters that end one command and start another in `sh`, outside of quotes.
+/// Subshells are included so `(...)` and `$(...)` are checked on their own.
+const SEGMENT_SEPARATORS: &[char] = &[';', '&', '|', '\n', '(', ')'];
+
+/// What a command substitution or subshell was opened in, to return to once it closes.
+enum Nesting {
+    Parenthesis { in_double_quotes: bool },
+    Backtick { in_double_quotes: bool },
+}
+
+/// Reserved words that can come before a command without being one, like `do` in
+/// `for f in *; do rm "$f"; done`, or that end a compound command and run nothing.
+const RESERVED_WORDS: &[&str] = &["!", "{", "}", "if", "then", "else", "elif", "fi", "do", "done", "while", "until"];
+
+/// Programs that run the rest of their arguments as another command, with the
+/// options of each that take a separate value and the number of operands, like
+/// the duration of `timeout`, that come before the command.
+const WRAPPERS: &[(&str, &[&str], usize)] = &[
+    ("env", &["-u", "-C", "-S", "--unset", "--chdir", "--split-string"], 0),
+    (
+        "sudo",
+        &["-u", "-g", "-h", "-p", "-C", "-D", "-r", "-t", "-U", "-T", "--user", "--group"],
+        0,
+    ),
+    ("doas", &["-a", "-C", "-u"], 0),
+    ("command", &[], 0),
+    ("exec", &["-a"], 0),
+    ("nice", &["-n", "--adjustment"], 0),
+    ("ionice", &["-c", "-n", "-p", "-P", "-u", "--class", "--classdata"], 0),
+    ("nohup", &[], 0),
+    ("setsid", &[], 0),
+    ("stdbuf", &["-i", "-o", "-e", "--input", "--output", "--error"], 0),
+    ("time", &["-f", "-o", "--format", "--output"], 0),
+    ("timeout", &["-k", "-s", "--kill-after", "--signal"], 1),
+    (
+        "xargs",
+        &["-a", "-d", "-E", "-I", "-L", "-n", "-P", "-s", "--arg-file", "--delimiter", "--max-args"],
+        0,
+    ),
+];
+
+/// Shells that run the string after `-c` as a command line of its own.
+const SHELLS: &[&str] = &["sh", "ash", "bash", "dash", "ksh", "mksh", "zsh", "fish"];
+
+struct CompiledRule {
+    rule: PolicyRule,
+    regex: Option<Regex>,
+}
+
+pub enum PolicyDecision {
+    Allowed,
+    Denied { segment: String, reason: String },
+}
+
+impl fmt::Display for PolicyDecision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyDecision::Allowed => write!(f, "allowed"),
+            PolicyDecision::Denied { segment, reason } => {
+                write!(f, "`{}` is denied by {}", segment, reason)
+            }
+        }
+    }
+}
+
+/// Allow/deny rules for `/exec` and `/bg`, checked against every command in a
+/// shell pipeline or list, including those run through wrappers like `sudo` or
+/// `timeout` and the command lines given to `sh -c` or `eval`. The first
+/// matching rule wins; otherwise the default applies. Allow rules skip commands
+/// that redirect to or from files unless they permit it.
+///
+/// Commands are parsed the way `sh` would only as far as quoting goes, so
+/// variables, aliases or scripts can still hide a program.
+pub struct Policy {
+    rules: Vec<CompiledRule>,
+    default_action: PolicyAction,
+    confirm_patterns: Vec<Regex>,
+}
+
+impl Policy {
+    pub fn new(config: &PolicyConfig) -> Result<Self, BotError> {
+        let rules = config
+            .rules
+            .iter()
+            .map(|rule| {
+                let regex = rule
+                    .regex
+                    .as_deref()
+                    .map(Regex::new)
+                    .transpose()
+                    .map_err(|e| BotError::ConfigError(format!("Invalid policy regex: {}", e)))?;
+
+                Ok(CompiledRule {
+                    rule: rule.clone(),
+                    regex,
+                })
+            })
+            .collect::<Result<Vec<_>, BotError>>()?;
+
+        let confirm_patterns = config
+            .confirm_patterns
+            .iter()
+            .map(|pattern| {
+                Regex::new(pattern).map_err(|e| {
+                    BotError::ConfigError(format!("Invalid confirm pattern '{}': {}", pattern, e))
+                })
+            })
+            .collect::<Result<Vec<_>, BotError>>()?;
+
+        Ok(Policy {
+            rules,
+            default_action: config.default_action,
+            confirm_patterns,
+        })
+    }
+
+    /// Every command that would run, wrapped ones included, has to be allowed.
+    pub fn check(&self, command: &str, role: Role, user_id: i64) -> PolicyDecision {
+        for segment in Self::split_segments(command) {
+            let redirects = has_redirection(&segment);
+            for words in Self::command_views(&segment) {
+                let (action, reason) = self.evaluate(&words, redirects, role, user_id);
+                if action == PolicyAction::Deny {
+                    return PolicyDecision::Denied { segment, reason };
+                }
+
+                if let Some(script) = inline_script(&words) {
+                    if let denied @ PolicyDecision::Denied { .. } = self.check(&script, role, user_id) {
+                        return denied;
+                    }
+                }
+            }
+        }
+
+        PolicyDecision::Allowed
+    }
+
+    /// Whether any command in the line matches a pattern that needs confirmation.
+    pub fn needs_confirmation(&self, command: &str) -> bool {
+        Self::command_texts(command).iter().any(|text| {
+            self.confirm_patterns
+                .iter()
+                .any(|pattern| pattern.is_match(text))
+        })
+    }
+
+    /// Every command the line would run, wrapped ones included, unquoted and joined
+    /// by single spaces, for matching patterns written against plain commands.
+    pub fn command_texts(command: &str) -> Vec<String> {
+        let mut texts = Vec::new();
+
+        for segment in Self::split_segments(command) {
+            for words in Self::command_views(&segment) {
+                texts.push(words.join(" "));
+                if let Some(script) = inline_script(&words) {
+                    texts.extend(Self::command_texts(&script));
+                }
+            }
+        }
+
+        texts
+    }
+
+    fn evaluate(&self, words: &[String], redirects: bool, role: Role, user_id: i64) -> (PolicyAction, String) {
+        for (index, compiled) in self.rules.iter().enumerate() {
+            if Self::rule_matches(compiled, words, redirects, role, user_id) {
+                let reason = match &compiled.rule.description {
+                    Some(description) => format!("rule #{} ({})", index + 1, description),
+                    None => format!("rule #{}", index + 1),
+                };
+                return (compiled.rule.action, reason);
+            }
+        }
+
+        (self.default_action, "the default policy".to_string())
+    }
+
+    fn rule_matches(
+        compiled: &CompiledRule,
+        words: &[String],
+        redirects: bool,
+        role: Role,
+        user_id: i64,
+    ) -> bool {
+        let rule = &compiled.rule;
+        let segment = words.join(" ");
+
+        if !rule.roles.is_empty() && !rule.roles.contains(&role) {
+            return false;
+        }
+        // A glob like `systemctl status *` must not also grant writing files
+        if redirects && rule.action == PolicyAction::Allow && !rule.allow_redirections {
+            return false;
+        }
+        if !rule.users.is_empty() && !rule.users.contains(&user_id) {
+            return false;
+        }
+
+        if let Some(program) = &rule.program {
+            // Deny rules look past `NAME=value` prefixes, allow rules must not let them through
+            let first_word = if rule.action == PolicyAction::Deny {
+                words.iter().find(|word| !is_assignment(word))
+            } else {
+                words.first()
+            };
+
+            if first_word.map(|word| program_name(word)).as_deref() != Some(program.as_str()) {
+                return false;
+            }
+        }
+
+        if let Some(pattern) = &rule.pattern {
+            if !glob_matches(pattern, &segment) {
+                return false;
+            }
+        }
+
+        if let Some(regex) = &compiled.regex {
+            if !regex.is_match(&segment) {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// The segment's words without leading reserved words, followed by the words of
+    /// each command a wrapper in it would run, e.g. `then sudo -u root rm -rf x`
+    /// yields `sudo -u root rm -rf x` and `rm -rf x`.
+    fn command_views(segment: &str) -> Vec<Vec<String>> {
+        let words = shell_words(segment);
+        let mut index = words
+            .iter()
+            .take_while(|word| RESERVED_WORDS.contains(&word.as_str()))
+            .count();
+        let mut views = Vec::new();
+        if index < words.len() {
+            views.push(words[index..].to_vec());
+        }
+
+        loop {
+            while words.get(index).is_some_and(|word| is_assignment(word)) {
+                index += 1;
+            }
+            let Some(word) = words.get(index) else {
+                break;
+            };
+            let name = program_name(word);
+            let Some((_, options_with_values, operands)) =
+                WRAPPERS.iter().find(|(wrapper, _, _)| *wrapper == name)
+            else {
+                break;
+            };
+
+            index += 1;
+            while let Some(option) = words.get(index).filter(|word| word.starts_with('-')) {
+                index += 1;
+                if option == "--" {
+                    break;
+                }
+                if options_with_values.contains(&option.as_str()) {
+                    index += 1;
+                }
+            }
+            index += operands;
+
+            if index < words.len() {
+                views.push(words[index..].to_vec());
+            }
+        }
+
+        views
+    }
+
+    /// Splits a shell command line into the individual commands it would run,
+    /// ignoring separators inside quotes and normalizing whitespace. Inside double
+    /// quotes only `$(` and backticks start a new command.
+    fn split_segments(command: &str) -> Vec<String> {
+        let mut segments = Vec::new();
+        let mut current = String::new();
+        let mut nesting = Vec::new();
+        let mut in_single_quotes = false;
+        let mut in_double_quotes = false;
+        let mut chars = command.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            if in_single_quotes {
+                in_single_quotes = c != '\'';
+                current.push(c);
+                continue;
+            }
+
+            match c {
+                '\'' if !in_double_quotes => {
+                    in_single_quotes = true;
+                    current.push(c);
+                }
+                '"' => {
+                    in_double_quotes = !in_double_quotes;
+                    current.push(c);
+                }
+                '\\' => {
+                    current.push(c);
+                    current.extend(chars.next());
+                }
+                '$' if chars.peek() == Some(&'(') => {
+                    chars.next();
+                    segments.push(std::mem::take(&mut current));
+                    nesting.push(Nesting::Parenthesis { in_double_quotes });
+                    in_double_quotes = false;
+                }
+                '`' => {
+                    segments.push(std::mem::take(&mut current));
+                    match nesting.last() {
+                        Some(Nesting::Backtick { in_double_quotes: outer }) => {
+                            in_double_quotes = *outer;
+                            nesting.pop();
+                        }
+                        _ => {
+                            nesting.push(Nesting::Backtick { in_double_quotes });
+                            in_double_quotes = false;
+                        }
+                    }
+                }
+                _ if in_double_quotes => current.push(c),
+                // `2>&1`, `>&2`, `&>file` and `>|file` are redirections, not separators
+                '&' if current.ends_with(['>', '<']) || chars.peek() == Some(&'>') => {
+                    current.push(c);
+                }
+                '|' if current.ends_with('>') => current.push(c),
+                '(' => {
+                    segments.push(std::mem::take(&mut current));
+                    nesting.push(Nesting::Parenthesis { in_double_quotes: false });
+                }
+                ')' => {
+                    segments.push(std::mem::take(&mut current));
+                    if let Some(Nesting::Parenthesis { in_double_quotes: outer }) = nesting.last() {
+                        in_double_quotes = *outer;
+                        nesting.pop();
+                    }
+                }
+                c if SEGMENT_SEPARATORS.contains(&c) => {
+                    segments.push(std::mem::take(&mut current));
+                }
+                _ => current.push(c),
+            }
+        }
+        segments.push(current);
+
+        segments
+            .into_iter()
+            .map(|segment| segment.split_whitespace().collect::<Vec<_>>().join(" "))
+            .filter(|segment| !segment.is_empty())
+            .collect()
+    }
+}
+
+/// Whether the command reads or writes a file through an unquoted `<`, `>`, `>>`,
+/// `>|` or `&>`. Duplicating descriptors, like `2>&1`, doesn't count.
+fn has_redirection(segment: &str) -> bool {
+    let mut chars = segment.chars().peekable();
+    let mut in_single_quotes = false;
+    let mut in_double_quotes = false;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' if !in_double_quotes => in_single_quotes = !in_single_quotes,
+            '"' if !in_single_quotes => in_double_quotes = !in_double_quotes,
+            '\\' if !in_single_quotes => {
+                chars.next();
+            }
+            '<' | '>' if !in_single_quotes && !in_double_quotes => {
+                while chars.next_if(|c| matches!(c, '<' | '>' | '|')).is_some() {}
+
+                if chars.next_if_eq(&'&').is_none() {
+                    return true;
+                }
+                // `>&1` and `<&-` name a descriptor, `>& file` is a file again
+                let mut target = String::new();
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                    target.push(c);
+                }
+                let is_descriptor =
+                    target == "-" || (!target.is_empty() && target.chars().all(|c| c.is_ascii_digit()));
+                if !is_descriptor {
+                    return true;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    false
+}
+
+/// Splits a command into words and removes quoting like `sh` would, so `'rm'`,
+/// `"rm"`, `\rm` and `r''m` are all `rm`. Expansions are left as they are.
+fn shell_words(segment: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut current = String::new();
+    let mut in_word = false;
+    let mut chars = segment.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut current));
+                    in_word = false;
+                }
+            }
+            '\\' => {
+                in_word = true;
+                if let Some(escaped) = chars.next() {
+                    current.push(escaped);
+                }
+            }
+            '\'' => {
+                in_word = true;
+                for quoted in chars.by_ref() {
+                    if quoted == '\'' {
+                        break;
+                    }
+                    current.push(quoted);
+                }
+            }
+            '"' => {
+                in_word = true;
+                while let Some(quoted) = chars.next() {
+                    match quoted {
+                        '"' => break,
+                        '\\' if matches!(chars.peek(), Some('$' | '`' | '"' | '\\' | '\n')) => {
+                            current.extend(chars.next());
+                        }
+                        _ => current.push(quoted),
+                    }
+                }
+            }
+            _ => {
+                in_word = true;
+                current.push(c);
+            }
+        }
+    }
+    if in_word {
+        words.push(current);
+    }
+
+    words
+}
+
+/// The command line a command runs through `eval` or a shell's `-c`, e.g.
+/// `rm -rf x` for `sh -c 'rm -rf x'`, to be checked like one given directly.
+fn inline_script(words: &[String]) -> Option<String> {
+    let mut words = words.iter().skip_while(|word| is_assignment(word));
+    let name = program_name(words.next()?);
+
+    if name == "eval" {
+        let arguments: Vec<&str> = words.map(String::as_str).collect();
+        return Some(arguments.join(" "));
+    }
+    if !SHELLS.contains(&name.as_str()) {
+        return None;
+    }
+
+    // Like `bash -lc x` or `sh -e -c x`, the first operand is the command line
+    let mut runs_command = false;
+    while let Some(word) = words.next() {
+        match word.as_str() {
+            "--" => break,
+            "-o" | "+o" => {
+                words.next();
+            }
+            "--command" => return words.next().cloned(),
+            option if option.starts_with("--command=") => {
+                return option.strip_prefix("--command=").map(str::to_string);
+            }
+            option if option.starts_with("--") => {}
+            option if option.starts_with('-') || option.starts_with('+') => {
+                runs_command |= option.starts_with('-') && option.contains('c');
+            }
+            operand => return runs_command.then(|| operand.to_string()),
+        }
+    }
+
+    words.next().filter(|_| runs_command).cloned()
+}
+
+/// Whether the word is a `NAME=value` variable assignment.
+fn is_assignment(word: &str) -> bool {
+    word.split_once('=').is_some_and(|(name, _)| {
+        let mut chars = name.chars();
+        chars
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+    })
+}
+
+/// The program a word runs, without its directory.
+fn program_name(word: &str) -> String {
+    Path::new(word)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// Matches `text` against a glob where `*` matches any run of characters and `?` a single one.
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            // Let the last `*` swallow one more character and retry
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(action: PolicyAction, program: &str) -> PolicyRule {
+        PolicyRule {
+            action,
+            description: None,
+            program: Some(program.to_string()),
+            pattern: None,
+            regex: None,
+            roles: Vec::new(),
+            users: Vec::new(),
+            allow_redirections: false,
+        }
+    }
+
+    fn policy(default_action: PolicyAction, rules: Vec<PolicyRule>) -> Policy {
+        Policy::new(&PolicyConfig {
+            default_action,
+            rules,
+            confirm_patterns: Vec::new(),
+        })
+        .unwrap()
+    }
+
+    fn is_allowed(policy: &Policy, command: &str) -> bool {
+        matches!(policy.check(command, Role::Operator, 1), PolicyDecision::Allowed)
+    }
+
+    #[test]
+    fn split_segments_handles_separators_and_quotes() {
+        assert_eq!(
+            Policy::split_segments("ls -l | grep  x && echo $(whoami); id"),
+            vec!["ls -l", "grep x", "echo", "whoami", "id"]
+        );
+        assert_eq!(Policy::split_segments("echo 'a; b' | wc"), vec!["echo 'a; b'", "wc"]);
+        assert_eq!(Policy::split_segments("echo `id`"), vec!["echo", "id"]);
+        assert_eq!(Policy::split_segments(r"echo a\;b"), vec![r"echo a\;b"]);
+        assert!(Policy::split_segments(" ; ").is_empty());
+        assert_eq!(Policy::split_segments(r#"grep -E "a|b" f"#), vec![r#"grep -E "a|b" f"#]);
+        assert_eq!(Policy::split_segments("journalctl -u x 2>&1"), vec!["journalctl -u x 2>&1"]);
+        assert_eq!(Policy::split_segments("ls &> out >| log"), vec!["ls &> out >| log"]);
+        assert_eq!(
+            Policy::split_segments(r#"echo "a; $(rm "x y") `id` b""#),
+            vec![r#"echo "a;"#, r#"rm "x y""#, "id", r#"b""#]
+        );
+    }
+
+    #[test]
+    fn command_views_skip_reserved_words_and_wrappers() {
+        let views = |segment| Policy::command_views(segment);
+
+        assert_eq!(views("do rm -rf $f"), vec![vec!["rm", "-rf", "$f"]]);
+        assert_eq!(views("then ! dd if=/dev/zero"), vec![vec!["dd", "if=/dev/zero"]]);
+        assert_eq!(views("{ rm x"), vec![vec!["rm", "x"]]);
+        assert!(views("fi").is_empty());
+        assert_eq!(views("echo if"), vec![vec!["echo", "if"]]);
+        assert_eq!(
+            views("timeout -s KILL 5 rm -rf /"),
+            vec![vec!["timeout", "-s", "KILL", "5", "rm", "-rf", "/"], vec!["rm", "-rf", "/"]]
+        );
+        for segment in ["setsid rm x", "stdbuf -o L rm x", "ionice -c 3 rm x", "doas -u root rm x"] {
+            assert_eq!(views(segment).last().unwrap(), &vec!["rm", "x"], "{}", segment);
+        }
+    }
+
+    #[test]
+    fn inline_script_finds_shell_and_eval_command_lines() {
+        let script = |segment| inline_script(&shell_words(segment));
+
+        assert_eq!(script("sh -c 'rm -rf /'").as_deref(), Some("rm -rf /"));
+        assert_eq!(script("bash -lc 'rm -rf /'").as_deref(), Some("rm -rf /"));
+        assert_eq!(script("A=1 bash -e -o pipefail -c x").as_deref(), Some("x"));
+        assert_eq!(script("fish --command='rm x'").as_deref(), Some("rm x"));
+        assert_eq!(script("eval rm -rf '/'").as_deref(), Some("rm -rf /"));
+        assert_eq!(script("bash script.sh"), None);
+        assert_eq!(script("echo sh -c x"), None);
+    }
+
+    #[test]
+    fn has_redirection_ignores_quotes_and_descriptors() {
+        for segment in ["ls > x", "ls >> x", "ls >| x", "ls &> x", "ls >&x", "cat < x", "cat <<EOF", "ls 2>x"] {
+            assert!(has_redirection(segment), "{} has no redirection", segment);
+        }
+        for segment in ["ls 2>&1", "ls >&2", "ls <&-", "echo '>' \"<\"", r"echo \>"] {
+            assert!(!has_redirection(segment), "{} has a redirection", segment);
+        }
+    }
+
+    #[test]
+    fn glob_matches_wildcards() {
+        assert!(glob_matches("git *", "git status"));
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("a?c", "abc"));
+        assert!(glob_matches("*pager*", "journalctl --no-pager"));
+        assert!(glob_matches("a*b*c", "a_b_b_c"));
+        assert!(!glob_matches("git *", "gitk"));
+        assert!(!glob_matches("a?c", "ac"));
+        assert!(!glob_matches("a*b", "a_b_c"));
+    }
+
+    #[test]
+    fn shell_words_removes_quoting() {
+        assert_eq!(shell_words(r#"'rm' "-rf" \/tmp/x"#), vec!["rm", "-rf", "/tmp/x"]);
+        assert_eq!(shell_words("d''d if=/dev/zero"), vec!["dd", "if=/dev/zero"]);
+        assert_eq!(shell_words(r#"echo "a \"b\" c""#), vec!["echo", r#"a "b" c"#]);
+        assert_eq!(shell_words("echo ''"), vec!["echo", ""]);
+    }
+
+    #[test]
+    fn deny_rules_see_through_quoting_and_wrappers() {
+        let policy = policy(
+            PolicyAction::Allow,
+            vec![rule(PolicyAction::Deny, "rm"), rule(PolicyAction::Deny, "dd")],
+        );
+
+        for command in [
+            "rm -rf /tmp/x",
+            "/bin/rm -rf /tmp/x",
+            "'rm' -rf /tmp/x",
+            "\"rm\" -rf /tmp/x",
+            "\\rm -rf /tmp/x",
+            "X=1 rm -rf /tmp/x",
+            "env rm -rf /tmp/x",
+            "env -i A=1 rm -rf /tmp/x",
+            "sudo -u root rm -rf /tmp/x",
+            "command rm -rf /tmp/x",
+            "exec rm -rf /tmp/x",
+            "nice -n 10 rm -rf /tmp/x",
+            "find /tmp | xargs -I {} rm {}",
+            "sudo env nice rm -rf /tmp/x",
+            "d''d if=/dev/zero of=/dev/sda",
+            "echo ok; dd if=/dev/zero",
+            "for f in x; do rm -rf $f; done",
+            "if true; then dd if=/dev/zero of=/dev/sda; fi",
+            "while :; do rm x; done",
+            "! rm x",
+            "{ rm x; }",
+            "timeout 5 rm -rf /",
+            "timeout -k 1 5 rm -rf /",
+            "setsid rm -rf /",
+            "stdbuf -oL rm -rf /",
+            "ionice -c 3 rm -rf /",
+            "doas rm -rf /",
+            "sh -c 'rm -rf /'",
+            "bash -lc \"echo ok; rm -rf /\"",
+            "sudo sh -c 'rm -rf /'",
+            "eval rm -rf /",
+            "eval 'rm -rf /'",
+            "find . | xargs sh -c 'rm \"$@\"' _",
+        ] {
+            assert!(!is_allowed(&policy, command), "{} was allowed", command);
+        }
+
+        for command in [
+            "ls -l",
+            "echo rm",
+            "sudo ls",
+            "env A=1 ls",
+            "grep -r dd .",
+            "for f in x; do echo $f; done",
+            "timeout 5 ls",
+            "sh -c 'ls -l'",
+        ] {
+            assert!(is_allowed(&policy, command), "{} was denied", command);
+        }
+    }
+
+    #[test]
+    fn allow_rules_need_every_wrapped_command_allowed() {
+        let policy = policy(
+            PolicyAction::Deny,
+            vec![rule(PolicyAction::Allow, "ls"), rule(PolicyAction::Allow, "env")],
+        );
+
+        assert!(is_allowed(&policy, "ls -l"));
+        assert!(is_allowed(&policy, "'ls' -l"));
+        assert!(is_allowed(&policy, "env ls"));
+        assert!(!is_allowed(&policy, "env rm -rf /"));
+        assert!(!is_allowed(&policy, "sudo ls"));
+        assert!(!is_allowed(&policy, "LD_PRELOAD=./x.so ls"));
+        assert!(!is_allowed(&policy, "env LD_PRELOAD=./x.so ls"));
+        assert!(!is_allowed(&policy, "timeout 5 ls"));
+    }
+
+    #[test]
+    fn allow_rules_check_inline_scripts_on_their_own() {
+        let policy = policy(
+            PolicyAction::Deny,
+            vec![rule(PolicyAction::Allow, "ls"), rule(PolicyAction::Allow, "sh")],
+        );
+
+        assert!(is_allowed(&policy, "sh -c 'ls -l'"));
+        assert!(!is_allowed(&policy, "sh -c 'ls; rm x'"));
+        assert!(!is_allowed(&policy, "sh -c 'ls > /etc/passwd'"));
+        assert!(!is_allowed(&policy, "eval ls"));
+    }
+
+    #[test]
+    fn allow_rules_reject_redirections() {
+        let mut status = rule(PolicyAction::Allow, "systemctl");
+        status.program = None;
+        status.pattern = Some("systemctl status *".to_string());
+        let mut tee = rule(PolicyAction::Allow, "tee");
+        tee.allow_redirections = true;
+        let policy = policy(
+            PolicyAction::Deny,
+            vec![status, rule(PolicyAction::Allow, "journalctl"), rule(PolicyAction::Allow, "grep"), tee],
+        );
+
+        assert!(is_allowed(&policy, "systemctl status x"));
+        assert!(is_allowed(&policy, "journalctl -u x 2>&1"));
+        assert!(is_allowed(&policy, "journalctl -u x 2>&1 | grep -E \"a|b\""));
+        assert!(is_allowed(&policy, "journalctl | grep '>'"));
+        assert!(is_allowed(&policy, "tee > /tmp/x"));
+        assert!(!is_allowed(&policy, "systemctl status x > /etc/passwd"));
+        assert!(!is_allowed(&policy, "journalctl > /root/.bashrc"));
+        assert!(!is_allowed(&policy, "journalctl >> /root/.bashrc"));
+        assert!(!is_allowed(&policy, "journalctl >| /root/.bashrc"));
+        assert!(!is_allowed(&policy, "journalctl &> /root/.bashrc"));
+        assert!(!is_allowed(&policy, "grep x < /etc/shadow"));
+        assert!(!is_allowed(&policy, "grep \"$(rm x)\" f"));
+    }
+
+    #[test]
+    fn command_texts_lists_every_command_unquoted() {
+        assert_eq!(Policy::command_texts("ls; 'sudo' x"), vec!["ls", "sudo x", "x"]);
+        assert_eq!(Policy::command_texts("env sudo x"), vec!["env sudo x", "sudo x", "x"]);
+    }
+
+    #[test]
+    fn confirmation_sees_through_quoting() {
+        let policy = Policy::new(&PolicyConfig::default()).unwrap();
+
+        assert!(policy.needs_confirmation("rm -rf /tmp/x"));
+        assert!(policy.needs_confirmation("'rm' -rf /tmp/x"));
+        assert!(policy.needs_confirmation("env rm -rf /tmp/x"));
+        assert!(policy.needs_confirmation("ls; sudo reboot"));
+        assert!(!policy.needs_confirmation("ls -l"));
+    }
+
+    #[test]
+    fn confirmation_sees_through_compound_commands_and_wrappers() {
+        let policy = Policy::new(&PolicyConfig::default()).unwrap();
+
+        for command in [
+            r#"for f in *; do rm -rf "$f"; done"#,
+            "if true; then dd if=/dev/zero of=/dev/sda; fi",
+            "while :; do kill -9 1; done",
+            "! shred x",
+            "{ reboot; }",
+            "timeout 9 reboot",
+            "setsid shutdown now",
+            "doas systemctl poweroff",
+            "sh -c 'rm -rf /tmp/x'",
+            "eval mkfs.ext4 /dev/sda1",
+        ] {
+            assert!(policy.needs_confirmation(command), "{} ran without confirmation", command);
+        }
+
+        for command in ["for f in *; do echo $f; done", "timeout 9 ls", "sh -c 'echo rm'"] {
+            assert!(!policy.needs_confirmation(command), "{} needed confirmation", command);
+        }
+    }
+}
@@ -0,0 +1,162 @@
+use crate::errors::BotError;
+use crate::executor::shell_command;
+use crate::types::Config;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::Path;
+use std::process::Stdio;
+use tokio::process::Child;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+const READ_BUFFER_SIZE: usize = 4096;
+const TERMINAL_ROWS: u16 = 24;
+const TERMINAL_COLUMNS: u16 = 80;
+
+pub const CTRL_C: &[u8] = b"\x03";
+pub const CTRL_D: &[u8] = b"\x04";
+
+/// An interactive shell attached to a pseudo-terminal, so programs that
+/// check `isatty` (sudo, REPLs, pagers) behave as they would over SSH.
+pub struct PtyShell {
+    master: File,
+    child: Child,
+}
+
+impl PtyShell {
+    /// Starts the shell and returns it together with a channel yielding raw screen output.
+    /// The channel closes once the shell exits and the terminal is drained.
+    pub fn spawn(
+        current_dir: &Path,
+        environment: &HashMap<String, Option<String>>,
+        config: &Config,
+    ) -> Result<(Self, UnboundedReceiver<Vec<u8>>), BotError> {
+        let (master, slave) = Self::open_pty()?;
+
+        let stdio = |fd: &OwnedFd| -> Result<Stdio, BotError> {
+            fd.try_clone()
+                .map(Stdio::from)
+                .map_err(|e| BotError::ExecError(format!("Failed to duplicate terminal: {}", e)))
+        };
+
+        let mut command = shell_command(config, environment);
+        command
+            .arg("-i")
+            .current_dir(current_dir)
+            .env("TERM", "dumb")
+            .stdin(stdio(&slave)?)
+            .stdout(stdio(&slave)?)
+            .stderr(stdio(&slave)?)
+            .kill_on_drop(true);
+
+        // Make the terminal the controlling tty of a fresh session so job control and Ctrl-C work
+        unsafe {
+            command.pre_exec(|| {
+                if libc::setsid() == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                if libc::ioctl(0, libc::TIOCSCTTY as _, 0) == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+
+        let child = command
+            .spawn()
+            .map_err(|e| BotError::ExecError(format!("Failed to start shell: {}", e)))?;
+
+        // The child holds its own copies now; keeping ours open would prevent EOF on exit
+        drop(slave);
+
+        let master = File::from(master);
+        let reader = master
+            .try_clone()
+            .map_err(|e| BotError::ExecError(format!("Failed to duplicate terminal: {}", e)))?;
+
+        Ok((PtyShell { master, child }, Self::spawn_reader(reader)))
+    }
+
+    fn open_pty() -> Result<(OwnedFd, OwnedFd), BotError> {
+        let mut master = -1;
+        let mut slave = -1;
+        let size = libc::winsize {
+            ws_row: TERMINAL_ROWS,
+            ws_col: TERMINAL_COLUMNS,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+
+        let result = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                std::ptr::null_mut(),
+                std::ptr::null(),
+                &size,
+            )
+        };
+
+        if result == -1 {
+            return Err(BotError::ExecError(format!(
+                "Failed to open pseudo-terminal: {}",
+                std::io::Error::last_os_error()
+            )));
+        }
+
+        // Neither end should leak into the shell beyond its stdio
+        for fd in [master, slave] {
+            unsafe {
+                libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+            }
+        }
+
+        unsafe { Ok((OwnedFd::from_raw_fd(master), OwnedFd::from_raw_fd(slave))) }
+    }
+
+    fn spawn_reader(mut reader: File) -> UnboundedReceiver<Vec<u8>> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        // Terminal reads are blocking, so they get a dedicated thread
+        std::thread::spawn(move || {
+            let mut buffer = [0u8; READ_BUFFER_SIZE];
+            loop {
+                match reader.read(&mut buffer) {
+                    Ok(0) | Err(_) => break,
+                    Ok(read) => {
+                        if sender.send(buffer[..read].to_vec()).is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+
+        receiver
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> Result<(), BotError> {
+        self.master
+            .write_all(data)
+            .and_then(|_| self.master.flush())
+            .map_err(|e| BotError::ExecError(format!("Failed to write to shell: {}", e)))
+    }
+
+    /// Whether the terminal waits for a line it doesn't echo, like the password
+    /// prompts of sudo, ssh or passwd. Line editors such as readline turn off
+    /// echoing too, but also line buffering, as they echo by themselves.
+    pub fn is_reading_secret(&self) -> bool {
+        let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+
+        // Without the terminal settings a secret can't be ruled out
+        if unsafe { libc::tcgetattr(self.master.as_raw_fd(), &mut termios) } == -1 {
+            return true;
+        }
+        termios.c_lflag & libc::ECHO == 0 && termios.c_lflag & libc::ICANON != 0
+    }
+
+    pub fn is_running(&mut self) -> bool {
+        matches!(self.child.try_wait(), Ok(None))
+    }
+}
@@ -0,0 +1,186 @@
+use crate::attachment::Attachment;
+use crate::errors::BotError;
+use crate::file_manager::FileManager;
+use crate::pty_shell::PtyShell;
+use crate::types::{FileItem, ListingOptions};
+use rand::RngExt;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+const MAX_HISTORY_LEN: usize = 100;
+
+/// An upload that would overwrite an existing file and waits for confirmation.
+pub struct PendingUpload {
+    pub attachment: Attachment,
+    pub target: PathBuf,
+}
+
+/// A dangerous command waiting for the user to press Run or Cancel.
+pub struct PendingCommand {
+    pub command: String,
+    pub cwd: PathBuf,
+    pub timeout: u64,
+    pub background: bool,
+    created_at: Instant,
+}
+
+impl PendingCommand {
+    pub fn new(command: String, timeout: u64, background: bool) -> Self {
+        PendingCommand {
+            command,
+            cwd: PathBuf::new(),
+            timeout,
+            background,
+            created_at: Instant::now(),
+        }
+    }
+}
+
+pub struct UserSession {
+    pub file_manager: FileManager,
+    /// Variables set with `/setenv`, or removed with `/unsetenv` when `None`
+    pub environment: HashMap<String, Option<String>>,
+    pub shell: Option<PtyShell>,
+    /// Entries of the last `/ls` listing, which the keyboard buttons refer to by index.
+    pub browser_entries: Vec<FileItem>,
+    pub browser_options: ListingOptions,
+    /// Changes with every listing and directory change; keyboards carry it so
+    /// presses on older listings can be told apart. Starts at a random value, so
+    /// keyboards from an expired session or before a restart don't match either.
+    pub browser_generation: u64,
+    pending_uploads: HashMap<u64, PendingUpload>,
+    next_upload_id: u64,
+    pending_commands: HashMap<u64, PendingCommand>,
+    next_command_id: u64,
+    history: Vec<String>,
+    last_activity: Instant,
+}
+
+impl UserSession {
+    fn new(working_directory: &str, root_directory: Option<&str>) -> Result<Self, BotError> {
+        Ok(UserSession {
+            file_manager: FileManager::new(working_directory, root_directory)?,
+            environment: HashMap::new(),
+            shell: None,
+            browser_entries: Vec::new(),
+            browser_options: ListingOptions::default(),
+            browser_generation: rand::rng().random(),
+            pending_uploads: HashMap::new(),
+            next_upload_id: 1,
+            pending_commands: HashMap::new(),
+            next_command_id: 1,
+            history: Vec::new(),
+            last_activity: Instant::now(),
+        })
+    }
+
+    /// Replaces the entries the `/ls` keyboards refer to.
+    pub fn set_browser_entries(&mut self, entries: Vec<FileItem>) {
+        self.browser_entries = entries;
+        self.invalidate_listing();
+    }
+
+    /// Makes the keyboards of earlier listings stale, e.g. after `/cd`.
+    pub fn invalidate_listing(&mut self) {
+        self.browser_generation = self.browser_generation.wrapping_add(1);
+    }
+
+    pub fn record_command(&mut self, command: &str) {
+        if self.history.len() == MAX_HISTORY_LEN {
+            self.history.remove(0);
+        }
+        self.history.push(command.to_string());
+    }
+
+    pub fn get_history(&self) -> &[String] {
+        &self.history
+    }
+
+    pub fn add_pending_upload(&mut self, upload: PendingUpload) -> u64 {
+        let id = self.next_upload_id;
+        self.next_upload_id += 1;
+        self.pending_uploads.insert(id, upload);
+        id
+    }
+
+    pub fn take_pending_upload(&mut self, id: u64) -> Option<PendingUpload> {
+        self.pending_uploads.remove(&id)
+    }
+
+    pub fn add_pending_command(&mut self, pending: PendingCommand, window: Duration) -> u64 {
+        self.pending_commands
+            .retain(|_, pending| pending.created_at.elapsed() < window);
+
+        let id = self.next_command_id;
+        self.next_command_id += 1;
+        self.pending_commands.insert(id, pending);
+        id
+    }
+
+    /// Returns the command unless it has been waiting longer than `window`.
+    pub fn take_pending_command(&mut self, id: u64, window: Duration) -> Option<PendingCommand> {
+        self.pending_commands
+            .remove(&id)
+            .filter(|pending| pending.created_at.elapsed() < window)
+    }
+
+    /// Returns the interactive shell if one is attached and still alive.
+    pub fn active_shell(&mut self) -> Option<&mut PtyShell> {
+        if !self.shell.as_mut().is_some_and(|shell| shell.is_running()) {
+            self.shell = None;
+        }
+        self.shell.as_mut()
+    }
+}
+
+pub struct SessionManager {
+    sessions: HashMap<i64, UserSession>,
+    working_directory: String,
+    root_directory: Option<String>,
+    idle_timeout: Duration,
+}
+
+impl SessionManager {
+    pub fn new(
+        working_directory: &str,
+        root_directory: Option<&str>,
+        idle_timeout: Duration,
+    ) -> Result<Self, BotError> {
+        // Fail early if the working directory is unusable instead of on the first command
+        FileManager::new(working_directory, root_directory)?;
+
+        Ok(SessionManager {
+            sessions: HashMap::new(),
+            working_directory: working_directory.to_string(),
+            root_directory: root_directory.map(|root| root.to_string()),
+            idle_timeout,
+        })
+    }
+
+    pub fn get_session(&mut self, user_id: i64) -> Result<&mut UserSession, BotError> {
+        self.remove_idle_sessions();
+
+        let session = match self.sessions.entry(user_id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(UserSession::new(
+                &self.working_directory,
+                self.root_directory.as_deref(),
+            )?),
+        };
+
+        session.last_activity = Instant::now();
+        Ok(session)
+    }
+
+    pub fn remove_session(&mut self, user_id: i64) {
+        self.sessions.remove(&user_id);
+    }
+
+    fn remove_idle_sessions(&mut self) {
+        let idle_timeout = self.idle_timeout;
+        self.sessions
+            .retain(|_, session| session.last_activity.elapsed() < idle_timeout);
+    }
+}
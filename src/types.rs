@@ -1,22 +1,334 @@
+use crate::executor::ExecResult;
+use chrono::{DateTime, Local};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
 use std::path::PathBuf;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub telegram_token: String,
     pub auth_file_path: String,
-    pub log_file_path: String
+    pub log_file_path: String,
+    #[serde(default = "default_audit_log_path")]
+    pub audit_log_path: String,
+    /// Log files are rotated before they grow past this size, 0 disables it.
+    #[serde(default = "default_log_max_size_bytes")]
+    pub log_max_size_bytes: u64,
+    #[serde(default = "default_true")]
+    pub log_rotate_daily: bool,
+    #[serde(default = "default_log_retained_files")]
+    pub log_retained_files: usize,
+    #[serde(default)]
+    pub log_compress: bool,
+    /// Kept separately from `log_retained_files`, audits are usually needed longer.
+    #[serde(default = "default_audit_log_retained_files")]
+    pub audit_log_retained_files: usize,
+    pub working_directory: String,
+    #[serde(default)]
+    pub root_directory: Option<String>,
+    #[serde(default = "default_session_idle_timeout_secs")]
+    pub session_idle_timeout_secs: u64,
+    #[serde(default = "default_exec_update_interval_secs")]
+    pub exec_update_interval_secs: u64,
+    #[serde(default = "default_exec_timeout_secs")]
+    pub exec_timeout_secs: u64,
+    /// Longer output is shortened to its head and tail, with the full streams attached.
+    #[serde(default = "default_output_attachment_threshold_chars")]
+    pub output_attachment_threshold_chars: usize,
+    #[serde(default)]
+    pub compress_output_attachments: bool,
+    /// Output beyond this many bytes per stream is dropped.
+    #[serde(default = "default_exec_max_output_bytes")]
+    pub exec_max_output_bytes: usize,
+    /// Show stdout and stderr mixed in arrival order instead of in two sections.
+    #[serde(default)]
+    pub exec_interleave_output: bool,
+    /// The shell commands run in, e.g. bash, zsh or fish, and the arguments placed
+    /// before the command.
+    #[serde(default = "default_exec_shell")]
+    pub exec_shell: String,
+    #[serde(default = "default_exec_shell_args")]
+    pub exec_shell_args: Vec<String>,
+    /// Start the shell with `-l`, so profile files can set up PATH and friends.
+    #[serde(default)]
+    pub exec_login_shell: bool,
+    #[serde(default)]
+    pub exec_environment: BaseEnvironment,
+    #[serde(default)]
+    pub exec_ansi_mode: AnsiMode,
+    /// Monospace font for `exec_ansi_mode: "image"`.
+    #[serde(default = "default_ansi_font_path")]
+    pub ansi_font_path: PathBuf,
+    #[serde(default = "default_max_upload_size_bytes")]
+    pub max_upload_size_bytes: u64,
+    #[serde(default = "default_ls_page_size")]
+    pub ls_page_size: usize,
+    #[serde(default = "default_role")]
+    pub default_role: Role,
+    #[serde(default)]
+    pub exec_policy: PolicyConfig,
+    #[serde(default = "default_access_code_ttl_secs")]
+    pub access_code_ttl_secs: u64,
+    #[serde(default = "default_access_code_max_attempts")]
+    pub access_code_max_attempts: u32,
+    #[serde(default = "default_auth_max_failures")]
+    pub auth_max_failures: u32,
+    #[serde(default = "default_auth_lockout_secs")]
+    pub auth_lockout_secs: u64,
+    /// Authorizations older than this have to be renewed, 0 keeps them forever.
+    #[serde(default = "default_auth_max_age_secs")]
+    pub auth_max_age_secs: u64,
+    /// Authorizations unused for this long expire, 0 disables the check.
+    #[serde(default = "default_auth_idle_timeout_secs")]
+    pub auth_idle_timeout_secs: u64,
+    /// How long the Run/Cancel buttons of a dangerous command stay valid.
+    #[serde(default = "default_confirmation_timeout_secs")]
+    pub confirmation_timeout_secs: u64,
+    #[serde(default = "default_totp_issuer")]
+    pub totp_issuer: String,
+    /// Regexes of commands that need a recent `/totp` confirmation.
+    #[serde(default)]
+    pub totp_sensitive_patterns: Vec<String>,
+    #[serde(default = "default_totp_freshness_secs")]
+    pub totp_freshness_secs: u64,
+}
+
+fn default_audit_log_path() -> String {
+    "audit.log".to_string()
+}
+
+fn default_log_max_size_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_log_retained_files() -> usize {
+    7
+}
+
+fn default_audit_log_retained_files() -> usize {
+    90
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_session_idle_timeout_secs() -> u64 {
+    3600
+}
+
+fn default_exec_update_interval_secs() -> u64 {
+    3
+}
+
+fn default_exec_timeout_secs() -> u64 {
+    3600
+}
+
+fn default_output_attachment_threshold_chars() -> usize {
+    3500
+}
+
+fn default_exec_max_output_bytes() -> usize {
+    16 * 1024 * 1024
+}
+
+fn default_exec_shell() -> String {
+    if cfg!(target_os = "windows") {
+        "cmd".to_string()
+    } else {
+        "sh".to_string()
+    }
+}
+
+fn default_exec_shell_args() -> Vec<String> {
+    if cfg!(target_os = "windows") {
+        vec!["/C".to_string()]
+    } else {
+        vec!["-c".to_string()]
+    }
+}
+
+fn default_ansi_font_path() -> PathBuf {
+    PathBuf::from("/usr/share/fonts/truetype/dejavu/DejaVuSansMono.ttf")
+}
+
+fn default_role() -> Role {
+    Role::Viewer
+}
+
+fn default_ls_page_size() -> usize {
+    20
+}
+
+fn default_access_code_ttl_secs() -> u64 {
+    300
+}
+
+fn default_access_code_max_attempts() -> u32 {
+    3
+}
+
+fn default_auth_max_failures() -> u32 {
+    5
+}
+
+fn default_auth_lockout_secs() -> u64 {
+    60
+}
+
+fn default_auth_max_age_secs() -> u64 {
+    30 * 24 * 3600
+}
+
+fn default_auth_idle_timeout_secs() -> u64 {
+    7 * 24 * 3600
+}
+
+fn default_confirmation_timeout_secs() -> u64 {
+    60
+}
+
+fn default_totp_issuer() -> String {
+    "telebash".to_string()
+}
+
+fn default_totp_freshness_secs() -> u64 {
+    300
+}
+
+fn default_max_upload_size_bytes() -> u64 {
+    // Bots can't download anything larger through the Bot API
+    20 * 1024 * 1024
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct AuthorizedUser {
-    pub user_id: u64,
+pub struct AuthorizedUsers {
+    pub users: HashMap<i64, UserInfo>,
+    #[serde(default)]
+    pub totp: HashMap<i64, TotpEnrollment>,
+    /// Roles of users who logged out or whose authorization expired, given back
+    /// when they sign in again with an access code.
+    #[serde(default)]
+    pub signed_out_roles: HashMap<i64, Role>,
+    /// Unredeemed `/invite` codes, so they survive a restart.
+    #[serde(default)]
+    pub invites: HashMap<String, Invite>,
 }
 
-#[derive(Debug, Clone)]
-pub struct UserSession {
-    pub current_dir: PathBuf,
+/// A one-time code that authorizes whoever redeems it with the role.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Invite {
+    pub role: Role,
+    /// Unix time in seconds
+    pub expires_at: u64,
+}
+
+/// A TOTP secret handed out by an admin. Lets the user authorize with `/auth <totp>`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TotpEnrollment {
+    pub secret: String,
+    pub role: Role,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserInfo {
     pub user_id: i64,
+    pub username: Option<String>,
+    pub authorized_at: String,
+    #[serde(default = "legacy_role")]
+    pub role: Role,
+    #[serde(default)]
+    pub last_seen: Option<u64>,
+}
+
+// Users authorized before roles existed could already do everything
+fn legacy_role() -> Role {
+    Role::Admin
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Viewer,
+    Operator,
+    Admin,
+}
+
+impl Role {
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        match permission {
+            Permission::ViewFiles | Permission::Download => true,
+            Permission::Upload | Permission::Exec => *self >= Role::Operator,
+            Permission::Shell | Permission::ManageUsers | Permission::ViewAudit => {
+                *self == Role::Admin
+            }
+        }
+    }
+
+    pub fn promoted(&self) -> Option<Role> {
+        match self {
+            Role::Viewer => Some(Role::Operator),
+            Role::Operator => Some(Role::Admin),
+            Role::Admin => None,
+        }
+    }
+
+    pub fn demoted(&self) -> Option<Role> {
+        match self {
+            Role::Viewer => None,
+            Role::Operator => Some(Role::Viewer),
+            Role::Admin => Some(Role::Operator),
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "viewer" => Ok(Role::Viewer),
+            "operator" => Ok(Role::Operator),
+            "admin" => Ok(Role::Admin),
+            other => Err(format!("Unknown role: {}", other)),
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::Viewer => write!(f, "viewer"),
+            Role::Operator => write!(f, "operator"),
+            Role::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    ViewFiles,
+    Download,
+    Upload,
+    /// Running commands, subject to the exec policy
+    Exec,
+    Shell,
+    ManageUsers,
+    ViewAudit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    File,
+    Directory,
+    Symlink,
+    Socket,
+    Fifo,
+    BlockDevice,
+    CharDevice,
 }
 
 #[derive(Debug, Clone)]
@@ -25,4 +337,212 @@ pub struct FileItem {
     pub path: PathBuf,
     pub is_directory: bool,
     pub size: u64,
+    pub kind: FileKind,
+    pub mode: u32,
+    pub owner: String,
+    pub group: String,
+    pub modified: Option<DateTime<Local>>,
+    pub symlink_target: Option<PathBuf>,
+}
+
+impl FileItem {
+    pub fn is_hidden(&self) -> bool {
+        self.name.starts_with('.')
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.kind == FileKind::File && self.mode & 0o111 != 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SortOrder {
+    #[default]
+    Name,
+    Time,
+    Size,
+}
+
+/// Flags accepted by `/ls`, mirroring the coreutils ones.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ListingOptions {
+    pub long: bool,
+    pub all: bool,
+    pub sort: SortOrder,
+}
+
+impl ListingOptions {
+    pub fn parse(args: &str) -> Result<Self, String> {
+        let mut options = ListingOptions::default();
+
+        for arg in args.split_whitespace() {
+            let flags = arg
+                .strip_prefix('-')
+                .ok_or_else(|| format!("Unexpected argument: {}", arg))?;
+
+            for flag in flags.chars() {
+                match flag {
+                    'l' => options.long = true,
+                    'a' => options.all = true,
+                    't' => options.sort = SortOrder::Time,
+                    'S' => options.sort = SortOrder::Size,
+                    _ => return Err(format!("Unknown flag: -{}", flag)),
+                }
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+/// The environment commands start with, before the session's `/setenv` variables.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BaseEnvironment {
+    /// Everything the bot itself was started with
+    #[default]
+    Inherit,
+    /// Nothing, best combined with a login shell that sets up PATH
+    Clear,
+    /// Only these variables
+    Explicit(HashMap<String, String>),
+}
+
+/// How colors and other escape sequences in command output are shown. Cursor
+/// movement and carriage returns are always interpreted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AnsiMode {
+    /// Styles are dropped
+    #[default]
+    Plain,
+    /// Bold, italic, underline and strikethrough as Telegram formatting, without monospace
+    Html,
+    /// Plain text, plus a colored PNG of the output
+    Image,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyAction {
+    Allow,
+    Deny,
+}
+
+/// A policy rule matches when every matcher it sets matches and the caller's
+/// role and id are in the lists (empty lists match everyone).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PolicyRule {
+    pub action: PolicyAction,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub program: Option<String>,
+    #[serde(default)]
+    pub pattern: Option<String>,
+    #[serde(default)]
+    pub regex: Option<String>,
+    #[serde(default)]
+    pub roles: Vec<Role>,
+    #[serde(default)]
+    pub users: Vec<i64>,
+    /// Allow rules never match commands that redirect to or from files, as
+    /// `journalctl > ~/.bashrc` would write anywhere, unless this is set.
+    #[serde(default)]
+    pub allow_redirections: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PolicyConfig {
+    /// `deny` with allow rules for known commands is the safe setup; deny rules on
+    /// top of `allow` are best-effort, since shell tricks can hide a program.
+    pub default_action: PolicyAction,
+    #[serde(default)]
+    pub rules: Vec<PolicyRule>,
+    /// Regexes of commands that are allowed but need an explicit confirmation.
+    #[serde(default = "default_confirm_patterns")]
+    pub confirm_patterns: Vec<String>,
+}
+
+fn default_confirm_patterns() -> Vec<String> {
+    [
+        r"^(sudo )?(\S*/)?(rm|rmdir|dd|shred|reboot|shutdown|poweroff|halt)\b",
+        r"^(sudo )?(\S*/)?mkfs",
+        r"^(sudo )?(\S*/)?kill -(9|KILL|SIGKILL)\b",
+        r"^(sudo )?(\S*/)?systemctl (reboot|poweroff|halt)\b",
+    ]
+    .iter()
+    .map(|pattern| pattern.to_string())
+    .collect()
+}
+
+impl Default for PolicyConfig {
+    // Without a configured policy only admins may run commands
+    fn default() -> Self {
+        PolicyConfig {
+            default_action: PolicyAction::Deny,
+            rules: vec![PolicyRule {
+                action: PolicyAction::Allow,
+                description: Some("admins may run anything".to_string()),
+                program: None,
+                pattern: None,
+                regex: None,
+                roles: vec![Role::Admin],
+                users: Vec::new(),
+                allow_redirections: true,
+            }],
+            confirm_patterns: default_confirm_patterns(),
+        }
+    }
+}
+
+/// One line of the audit log: who did what, where, and how it ended.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub timestamp: DateTime<Local>,
+    pub user_id: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    pub chat_id: i64,
+    pub command: String,
+    #[serde(default)]
+    pub arguments: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub policy: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signal: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_bytes: Option<usize>,
+}
+
+impl AuditEntry {
+    pub fn new(user_id: i64, username: Option<&str>, chat_id: i64, command: &str, arguments: &str) -> Self {
+        AuditEntry {
+            timestamp: Local::now(),
+            user_id,
+            username: username.map(|username| username.to_string()),
+            chat_id,
+            command: command.to_string(),
+            arguments: arguments.to_string(),
+            cwd: None,
+            policy: None,
+            exit_code: None,
+            signal: None,
+            duration_ms: None,
+            output_bytes: None,
+        }
+    }
+
+    pub fn set_result(&mut self, result: &ExecResult) {
+        self.exit_code = result.exit_code;
+        self.signal = result.signal;
+        self.duration_ms = Some(result.duration.as_millis() as u64);
+        self.output_bytes = Some(result.output_bytes());
+    }
 }